@@ -0,0 +1,522 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+};
+
+use anyhow::{Context, Result};
+use cargo_metadata::{
+    camino::{Utf8Path, Utf8PathBuf},
+    cargo_platform::{Cfg, Platform},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// The parts of cargo's configuration (i.e. `.cargo/config.toml` files) that affect how crates are
+/// compiled, and therefore how rust-analyzer should analyze them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CargoConfig {
+    /// The environment variables set with the `[env]` table, which are available to `env!`
+    pub env: HashMap<String, String>,
+
+    /// The flags passed to every invocation of rustc
+    pub rustflags: Vec<String>,
+}
+
+impl CargoConfig {
+    /// Resolves the effective config for cargo running in the given directory, using the same
+    /// layering as cargo: config files in the directory and its ancestors take precedence over
+    /// those further up the tree, and the config file in the cargo home has the lowest precedence.
+    /// Environment variables are merged with the config files the same way cargo merges them.
+    /// Config files that can't be read or parsed are skipped.
+    ///
+    /// The given cfgs of the target are used to match `target.'cfg(..)'` tables.
+    pub fn load(
+        cwd: &Utf8Path,
+        cargo_home: Option<&Utf8Path>,
+        target_triple: Option<&str>,
+        target_cfgs: &[Cfg],
+    ) -> Self {
+        Self::load_with_env(cwd, cargo_home, target_triple, target_cfgs, |key| {
+            env::var(key).ok()
+        })
+    }
+
+    /// Like [`CargoConfig::load`], but looks up environment variables with the given function
+    fn load_with_env(
+        cwd: &Utf8Path,
+        cargo_home: Option<&Utf8Path>,
+        target_triple: Option<&str>,
+        target_cfgs: &[Cfg],
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let files = config_files(cwd, cargo_home);
+        let mut env_vars = HashMap::new();
+        let mut build_rustflags = Vec::new();
+        let mut target_rustflags: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        // Apply the files from lowest to highest precedence, so that values from more specific
+        // files overwrite (or, for arrays, are appended to) those from less specific ones
+        for path in files.iter().rev() {
+            debug!(cargo_config_path = %path);
+            let file = match read_config_file(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Skipping cargo config: {e:#}");
+                    continue;
+                }
+            };
+
+            // Relative `[env]` paths are relative to the parent of the directory containing the
+            // config file (e.g. the directory containing `.cargo`)
+            let base = path.parent().and_then(|dir| dir.parent()).unwrap_or(cwd);
+
+            for (key, value) in file.env {
+                let value = value.resolve(env_var(&key), base);
+                env_vars.insert(key, value);
+            }
+
+            if let Some(rustflags) = file.build.rustflags {
+                build_rustflags.extend(rustflags.into_args());
+            }
+
+            for (key, target) in file.target {
+                if let Some(rustflags) = target.rustflags {
+                    target_rustflags
+                        .entry(key)
+                        .or_default()
+                        .extend(rustflags.into_args());
+                }
+            }
+        }
+
+        // Environment variables have the highest precedence, so they're appended to the values
+        // from the config files
+        if let Some(flags) = env_var("CARGO_BUILD_RUSTFLAGS") {
+            build_rustflags.extend(split_flags(&flags));
+        }
+
+        if let Some(triple) = target_triple {
+            let key = format!(
+                "CARGO_TARGET_{}_RUSTFLAGS",
+                triple.to_uppercase().replace(['-', '.'], "_")
+            );
+            if let Some(flags) = env_var(&key) {
+                target_rustflags
+                    .entry(triple.to_string())
+                    .or_default()
+                    .extend(split_flags(&flags));
+            }
+        }
+
+        // Like cargo, the flags of the target's own table come first, followed by those of every
+        // matching `cfg(..)` table
+        let mut matching_target_rustflags: Vec<String> = target_triple
+            .and_then(|triple| target_rustflags.remove(triple))
+            .unwrap_or_default();
+        for (key, flags) in target_rustflags {
+            match key.parse::<Platform>() {
+                Ok(Platform::Cfg(expr)) if expr.matches(target_cfgs) => {
+                    matching_target_rustflags.extend(flags)
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Skipping `target.{key}` in cargo config: {e}"),
+            }
+        }
+
+        // Only one source of rustflags is used, in the same order of precedence as cargo
+        let rustflags = if let Some(flags) = env_var("CARGO_ENCODED_RUSTFLAGS") {
+            flags
+                .split('\x1f')
+                .filter(|flag| !flag.is_empty())
+                .map(String::from)
+                .collect()
+        } else if let Some(flags) = env_var("RUSTFLAGS") {
+            split_flags(&flags)
+        } else if !matching_target_rustflags.is_empty() {
+            matching_target_rustflags
+        } else {
+            build_rustflags
+        };
+
+        Self {
+            env: env_vars,
+            rustflags,
+        }
+    }
+
+    /// Returns the target directory set with `build.target-dir` in the config files used by cargo
+    /// running in the given directory, if there is one
+    pub fn target_dir(cwd: &Utf8Path, cargo_home: Option<&Utf8Path>) -> Option<Utf8PathBuf> {
+        config_files(cwd, cargo_home).into_iter().find_map(|path| {
+            let target_dir = read_config_file(&path).ok()?.build.target_dir?;
+            // Like in `[env]`, relative paths are relative to the parent of the directory
+            // containing the config file
+            let base = path.parent().and_then(|dir| dir.parent()).unwrap_or(cwd);
+
+            Some(base.join(target_dir))
+        })
+    }
+
+    /// Returns the cfg options set with `--cfg` in the rustflags, in the form rust-analyzer expects
+    /// (e.g. `tokio_unstable` or `foo="bar"`)
+    pub fn cfgs(&self) -> Vec<String> {
+        let mut cfgs = Vec::new();
+        let mut flags = self.rustflags.iter();
+
+        while let Some(flag) = flags.next() {
+            if flag == "--cfg" {
+                cfgs.extend(flags.next().cloned());
+            } else if let Some(cfg) = flag.strip_prefix("--cfg=") {
+                cfgs.push(cfg.to_string());
+            }
+        }
+
+        cfgs
+    }
+}
+
+/// Returns the config files used by cargo running in the given directory, from highest to lowest
+/// precedence
+fn config_files(cwd: &Utf8Path, cargo_home: Option<&Utf8Path>) -> Vec<Utf8PathBuf> {
+    let mut files: Vec<Utf8PathBuf> = cwd
+        .ancestors()
+        .filter_map(|dir| find_config_file(&dir.join(".cargo")))
+        .collect();
+
+    if let Some(file) = cargo_home.and_then(find_config_file)
+        && !files.contains(&file)
+    {
+        files.push(file);
+    }
+
+    files
+}
+
+/// Returns the config file in the given directory. Like cargo, the legacy `config` file takes
+/// precedence over `config.toml` if both exist.
+fn find_config_file(dir: &Utf8Path) -> Option<Utf8PathBuf> {
+    ["config", "config.toml"]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn read_config_file(path: &Utf8Path) -> Result<ConfigFile> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read cargo config `{path}`"))?;
+
+    toml::from_str(&contents).with_context(|| format!("Failed to parse cargo config `{path}`"))
+}
+
+fn split_flags(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(String::from).collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    env: HashMap<String, EnvValue>,
+    build: BuildConfig,
+    target: BTreeMap<String, TargetConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BuildConfig {
+    rustflags: Option<Flags>,
+    #[serde(rename = "target-dir")]
+    target_dir: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TargetConfig {
+    rustflags: Option<Flags>,
+}
+
+/// Flags can be given as a space-separated string or as an array of strings
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Flags {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl Flags {
+    fn into_args(self) -> Vec<String> {
+        match self {
+            Self::String(flags) => split_flags(&flags),
+            Self::Array(flags) => flags,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EnvValue {
+    Value(String),
+    Table {
+        value: String,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        relative: bool,
+    },
+}
+
+impl EnvValue {
+    /// Returns the value that the variable has during compilation, given its value in the
+    /// environment. Unless the value is forced, variables that are already set in the environment
+    /// keep their value.
+    fn resolve(self, existing: Option<String>, base: &Utf8Path) -> String {
+        let (value, force, relative) = match self {
+            Self::Value(value) => (value, false, false),
+            Self::Table {
+                value,
+                force,
+                relative,
+            } => (value, force, relative),
+        };
+
+        if !force && let Some(existing) = existing {
+            return existing;
+        }
+
+        if relative {
+            base.join(value).to_string()
+        } else {
+            value
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /// A temporary directory that is removed when dropped
+    struct TempDir(Utf8PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = Utf8PathBuf::from_path_buf(env::temp_dir())
+                .unwrap()
+                .join(format!("cargo-subspace-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self(dir)
+        }
+
+        /// Writes `.cargo/config.toml` in the given subdirectory and returns the subdirectory
+        fn config(&self, dir: &str, contents: &str) -> Utf8PathBuf {
+            let dir = self.0.join(dir);
+            fs::create_dir_all(dir.join(".cargo")).unwrap();
+            fs::write(dir.join(".cargo/config.toml"), contents).unwrap();
+
+            dir
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const TRIPLE: &str = "x86_64-unknown-linux-gnu";
+
+    fn linux_cfgs() -> Vec<Cfg> {
+        ["unix", r#"target_os="linux""#]
+            .into_iter()
+            .map(|cfg| cfg.parse().unwrap())
+            .collect()
+    }
+
+    fn load(cwd: &Utf8Path, cargo_home: Option<&Utf8Path>, env: &[(&str, &str)]) -> CargoConfig {
+        CargoConfig::load_with_env(cwd, cargo_home, Some(TRIPLE), &linux_cfgs(), |key| {
+            env.iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn nested_configs_take_precedence() {
+        let temp = TempDir::new("nested-configs");
+        let cargo_home = temp.config(
+            "home",
+            r#"
+            [env]
+            FROM_HOME = "home"
+            SHARED = "home"
+            "#,
+        );
+        temp.config(
+            "workspace",
+            r#"
+            [env]
+            SHARED = "workspace"
+            [build]
+            rustflags = ["--cfg", "workspace"]
+            "#,
+        );
+        let cwd = temp.config(
+            "workspace/crate",
+            r#"
+            [env]
+            SHARED = "crate"
+            [build]
+            rustflags = "--cfg crate"
+            "#,
+        );
+
+        let config = load(&cwd, Some(&cargo_home.join(".cargo")), &[]);
+
+        assert_eq!(config.env["FROM_HOME"], "home");
+        assert_eq!(config.env["SHARED"], "crate");
+        assert_eq!(config.rustflags, ["--cfg", "workspace", "--cfg", "crate"]);
+    }
+
+    #[test]
+    fn env_respects_force_and_relative() {
+        let temp = TempDir::new("env-values");
+        let cwd = temp.config(
+            "workspace",
+            r#"
+            [env]
+            PLAIN = "config"
+            FORCED = { value = "config", force = true }
+            RELATIVE = { value = "data", relative = true }
+            "#,
+        );
+
+        let config = load(&cwd, None, &[("PLAIN", "env"), ("FORCED", "env")]);
+
+        assert_eq!(config.env["PLAIN"], "env");
+        assert_eq!(config.env["FORCED"], "config");
+        assert_eq!(config.env["RELATIVE"], cwd.join("data").as_str());
+    }
+
+    #[test]
+    fn unparsable_configs_are_skipped() {
+        let temp = TempDir::new("unparsable");
+        temp.config("workspace", "[env]\nVALID = \"outer\"");
+        let cwd = temp.config("workspace/crate", "[env\nBROKEN");
+
+        let config = load(&cwd, None, &[]);
+
+        assert_eq!(config.env["VALID"], "outer");
+    }
+
+    #[test]
+    fn rustflags_precedence() {
+        let temp = TempDir::new("rustflags-precedence");
+        let cwd = temp.config(
+            "workspace",
+            &format!(
+                r#"
+                [build]
+                rustflags = ["--cfg", "build"]
+                [target.{TRIPLE}]
+                rustflags = ["--cfg", "triple"]
+                "#
+            ),
+        );
+
+        let target_env = "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_RUSTFLAGS";
+        let rustflags = |env: &[(&str, &str)]| load(&cwd, None, env).rustflags;
+
+        assert_eq!(rustflags(&[]), ["--cfg", "triple"]);
+        assert_eq!(
+            rustflags(&[(target_env, "--cfg env")]),
+            ["--cfg", "triple", "--cfg", "env"]
+        );
+        assert_eq!(
+            rustflags(&[(target_env, "--cfg env"), ("RUSTFLAGS", "--cfg rustflags")]),
+            ["--cfg", "rustflags"]
+        );
+        assert_eq!(
+            rustflags(&[
+                ("RUSTFLAGS", "--cfg rustflags"),
+                ("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fencoded"),
+            ]),
+            ["--cfg", "encoded"]
+        );
+    }
+
+    #[test]
+    fn build_rustflags_without_target_rustflags() {
+        let temp = TempDir::new("build-rustflags");
+        let cwd = temp.config(
+            "workspace",
+            r#"
+            [build]
+            rustflags = ["--cfg", "build"]
+            [target.'cfg(windows)']
+            rustflags = ["--cfg", "windows"]
+            "#,
+        );
+
+        let config = load(&cwd, None, &[("CARGO_BUILD_RUSTFLAGS", "--cfg env")]);
+
+        assert_eq!(config.rustflags, ["--cfg", "build", "--cfg", "env"]);
+    }
+
+    #[test]
+    fn matching_cfg_tables_follow_the_triple() {
+        let temp = TempDir::new("cfg-tables");
+        let cwd = temp.config(
+            "workspace",
+            &format!(
+                r#"
+                [target.'cfg(unix)']
+                rustflags = ["--cfg", "unix"]
+                [target.'cfg(windows)']
+                rustflags = ["--cfg", "windows"]
+                [target.{TRIPLE}]
+                rustflags = ["--cfg", "triple"]
+                [target.aarch64-apple-darwin]
+                rustflags = ["--cfg", "darwin"]
+                "#
+            ),
+        );
+
+        let config = load(&cwd, None, &[]);
+
+        assert_eq!(config.rustflags, ["--cfg", "triple", "--cfg", "unix"]);
+    }
+
+    #[test]
+    fn cfgs_are_extracted_from_rustflags() {
+        let config = CargoConfig {
+            env: HashMap::new(),
+            rustflags: [
+                "--cfg",
+                "tokio_unstable",
+                "-Dwarnings",
+                r#"--cfg=foo="bar""#,
+                "--cfg",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+
+        assert_eq!(config.cfgs(), ["tokio_unstable", r#"foo="bar""#]);
+    }
+
+    #[test]
+    fn target_dir_is_relative_to_the_config() {
+        let temp = TempDir::new("target-dir");
+        temp.config("workspace", r#"build.target-dir = "out""#);
+        let cwd = temp.0.join("workspace/crate");
+        fs::create_dir_all(&cwd).unwrap();
+
+        assert_eq!(
+            CargoConfig::target_dir(&cwd, None),
+            Some(temp.0.join("workspace/out"))
+        );
+    }
+}
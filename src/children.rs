@@ -0,0 +1,385 @@
+use std::{
+    io::{self, Read},
+    ops::{Deref, DerefMut},
+    path::Path,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use tracing::debug;
+#[cfg(not(target_os = "windows"))]
+use tracing::warn;
+
+/// The child processes that are currently running
+static CHILDREN: Mutex<Vec<Handle>> = Mutex::new(Vec::new());
+
+/// A child process that can be terminated from other threads. Once a process has been reaped, its
+/// PID can be reused by an unrelated process, so it's only signalled while the lock is held and it
+/// hasn't been reaped.
+#[derive(Clone)]
+struct Handle {
+    pid: u32,
+    reaped: Arc<Mutex<bool>>,
+}
+
+impl Handle {
+    /// Terminates the process (and the processes it spawned), unless it has already been reaped
+    fn terminate(&self) {
+        let reaped = self.reaped.lock().unwrap_or_else(|e| e.into_inner());
+        if !*reaped {
+            terminate(self.pid);
+        }
+    }
+}
+
+/// A child process that is terminated, along with everything it spawned, if cargo-subspace is
+/// cancelled while it is running or if it is dropped before it exits.
+pub struct TrackedChild {
+    child: Child,
+    handle: Handle,
+}
+
+impl TrackedChild {
+    /// Waits for the child to exit, like [`Child::wait`]. It's marked as reaped before it actually
+    /// is, so that it can't be signalled by another thread after its PID has been released.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        #[cfg(not(target_os = "windows"))]
+        wait_for_exit(self.child.id())?;
+
+        let mut reaped = self.handle.reaped.lock().unwrap_or_else(|e| e.into_inner());
+        *reaped = true;
+
+        self.child.wait()
+    }
+}
+
+impl Deref for TrackedChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Self::Target {
+        &self.child
+    }
+}
+
+impl DerefMut for TrackedChild {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.child
+    }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        let pid = self.child.id();
+        {
+            let mut reaped = self.handle.reaped.lock().unwrap_or_else(|e| e.into_inner());
+            if !*reaped {
+                if let Ok(None) = self.child.try_wait() {
+                    debug!(pid, "terminating child process that is still running");
+                    terminate(pid);
+                    let _ = self.child.wait();
+                }
+
+                *reaped = true;
+            }
+        }
+
+        if let Ok(mut children) = CHILDREN.lock() {
+            children.retain(|child| child.pid != pid);
+        }
+    }
+}
+
+/// Spawns the given command, so that it can be terminated along with the compilers and build
+/// scripts it runs. It stays in our process group, since rust-analyzer cancels a discovery by
+/// killing that group.
+pub fn spawn(cmd: &mut Command) -> io::Result<TrackedChild> {
+    // Hold the lock while spawning, so that a cancellation can't miss the new process
+    let mut children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    let child = cmd.spawn()?;
+    let handle = Handle {
+        pid: child.id(),
+        reaped: Arc::new(Mutex::new(false)),
+    };
+    children.push(handle.clone());
+
+    Ok(TrackedChild { child, handle })
+}
+
+/// Runs the given command to completion and collects its output, like [`Command::output`]. If it
+/// runs for longer than the timeout, it is terminated and an error is returned.
+pub fn output(cmd: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let mut child = spawn(cmd)?;
+    let deadline = Deadline::start(&child, timeout);
+
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = pipe.read_to_end(&mut output);
+            output
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().unwrap()));
+    let stderr = read(Box::new(child.stderr.take().unwrap()));
+
+    let status = child.wait()?;
+    if deadline.expired() {
+        // e.g. `cargo metadata`
+        let program = Path::new(cmd.get_program())
+            .file_stem()
+            .unwrap_or(cmd.get_program());
+        let command = std::iter::once(program)
+            .chain(cmd.get_args().next())
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        return Err(anyhow!(
+            "`{command}` timed out after {}s",
+            timeout.unwrap_or_default().as_secs()
+        ));
+    }
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Terminates a child process (and the processes it spawned) if it is still running when a timeout
+/// elapses. The timeout is cancelled when the deadline is dropped, so it must be dropped as soon as
+/// the child has been waited on.
+pub struct Deadline {
+    expired: Arc<AtomicBool>,
+    _cancel: Option<mpsc::Sender<()>>,
+}
+
+impl Deadline {
+    /// Starts the timeout for the given child. If there is no timeout, the child is never
+    /// terminated.
+    pub fn start(child: &TrackedChild, timeout: Option<Duration>) -> Self {
+        let expired = Arc::new(AtomicBool::new(false));
+        let Some(timeout) = timeout else {
+            return Self {
+                expired,
+                _cancel: None,
+            };
+        };
+
+        let (cancel, cancelled) = mpsc::channel();
+        let handle = child.handle.clone();
+        let flag = expired.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) {
+                debug!(pid = handle.pid, "child process timed out");
+                flag.store(true, Ordering::SeqCst);
+                handle.terminate();
+            }
+        });
+
+        Self {
+            expired,
+            _cancel: Some(cancel),
+        }
+    }
+
+    /// Returns true if the child was terminated because the timeout elapsed
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
+
+/// Terminates every running child process (and the processes they spawned) and exits when
+/// cargo-subspace receives SIGTERM, SIGINT or SIGHUP, or when its stdout is closed. This is how
+/// rust-analyzer cancels a discover or check that it no longer needs.
+#[cfg(not(target_os = "windows"))]
+pub fn terminate_on_cancel() -> Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            warn!(signal, "cancelled; terminating child processes");
+            cancel(128 + signal);
+        }
+    });
+
+    // Only a pipe can be closed by the reader. On Linux, polling the write end of a pipe reports an
+    // error once the read end has been closed
+    if is_pipe(libc::STDOUT_FILENO) {
+        std::thread::spawn(|| {
+            let mut fd = libc::pollfd {
+                fd: libc::STDOUT_FILENO,
+                events: 0,
+                revents: 0,
+            };
+
+            loop {
+                // SAFETY: `fd` is a valid `pollfd`, and exactly one is passed
+                let ready = unsafe { libc::poll(&mut fd, 1, -1) };
+                if ready > 0 && fd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                    warn!("stdout was closed; terminating child processes");
+                    cancel(128 + libc::SIGPIPE);
+                }
+
+                // Stdout isn't open anymore, so polling it would return immediately forever
+                if ready > 0 && fd.revents & libc::POLLNVAL != 0 {
+                    return;
+                }
+
+                if ready < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    return;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn terminate_on_cancel() -> Result<()> {
+    Ok(())
+}
+
+/// Waits for the process with the given ID to exit, without reaping it
+#[cfg(not(target_os = "windows"))]
+fn wait_for_exit(pid: u32) -> io::Result<()> {
+    loop {
+        // SAFETY: `siginfo_t` is plain data, so an all-zero value is valid, and `waitid` only
+        // writes to it
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+
+        if result == 0 {
+            return Ok(());
+        }
+
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_pipe(fd: libc::c_int) -> bool {
+    // SAFETY: `stat` is plain data, so an all-zero value is valid, and it is only read if `fstat`
+    // succeeds
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::fstat(fd, &mut stat) };
+
+    result == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFIFO
+}
+
+/// Terminates every running child process and exits with the given code
+#[cfg(not(target_os = "windows"))]
+fn cancel(code: i32) -> ! {
+    let children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    for child in children.iter() {
+        child.terminate();
+    }
+
+    std::process::exit(code)
+}
+
+/// Sends SIGTERM to the process with the given ID and every process it spawned
+#[cfg(not(target_os = "windows"))]
+fn terminate(pid: u32) {
+    let parents = process_parents();
+    let mut pids = vec![pid];
+    let mut i = 0;
+    while let Some(&parent) = pids.get(i) {
+        pids.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+
+    // Parents are terminated before their children, so that they can't spawn any more of them
+    for pid in pids {
+        // SAFETY: `kill` has no memory safety requirements
+        let result = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        if result != 0 {
+            debug!(
+                pid,
+                "failed to terminate process: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Returns the ID of every running process, along with the ID of its parent
+#[cfg(target_os = "linux")]
+fn process_parents() -> Vec<(u32, u32)> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| {
+            let pid: u32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+            // The command name is in parentheses and may contain spaces, so the fields are
+            // counted from the last parenthesis. They start with the state, then the parent's ID
+            let ppid = stat
+                .rsplit_once(')')?
+                .1
+                .split_whitespace()
+                .nth(1)?
+                .parse()
+                .ok()?;
+
+            Some((pid, ppid))
+        })
+        .collect()
+}
+
+/// Returns the ID of every running process, along with the ID of its parent
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn process_parents() -> Vec<(u32, u32)> {
+    let Ok(output) = Command::new("ps").args(["-A", "-o", "pid=,ppid="]).output() else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.parse().ok()?, fields.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Terminates the process with the given ID and every process it spawned
+#[cfg(target_os = "windows")]
+fn terminate(pid: u32) {
+    let result = Command::new("taskkill")
+        .args(["/T", "/F", "/PID"])
+        .arg(pid.to_string())
+        .output();
+    if let Err(e) = result {
+        debug!(pid, "failed to terminate process tree: {e}");
+    }
+}
@@ -1,12 +1,17 @@
+use std::collections::HashSet;
+use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::Result;
-use cargo_metadata::camino::Utf8PathBuf;
-use clap::Parser;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use clap::{Args, Parser};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use crate::{ProjectJson, util::FilePathBuf};
+use crate::ProjectJson;
+use crate::config::Config;
 
 #[derive(PartialEq, Clone, Debug, Parser)]
 pub struct CargoSubspace {
@@ -19,6 +24,30 @@ pub struct CargoSubspace {
     #[arg(long, env = "CARGO_HOME")]
     pub cargo_home: Option<PathBuf>,
 
+    /// The rustup toolchain to use (e.g. `stable` or `nightly-2025-01-01`). If this flag is not
+    /// included, `cargo-subspace` uses the toolchain that rustup selects for the workspace, which
+    /// respects `rust-toolchain.toml` files.
+    #[arg(long, env = "CARGO_SUBSPACE_TOOLCHAIN")]
+    pub toolchain: Option<String>,
+
+    /// The path to a `cargo-subspace.toml` config file. If this flag is not included,
+    /// `cargo-subspace` will look for a config file in the ancestors of the discovered manifest.
+    #[arg(long, env = "CARGO_SUBSPACE_CONFIG")]
+    pub config: Option<Utf8PathBuf>,
+
+    #[command(flatten)]
+    pub network: NetworkArgs,
+
+    #[command(flatten)]
+    pub resources: ResourceArgs,
+
+    /// A dedicated target directory for the builds run by `discover` and `check`, e.g.
+    /// `target/subspace`. Using a separate directory from your normal builds avoids waiting on
+    /// cargo's build lock and rebuilds caused by differing flags. Overrides `target-dir` in
+    /// `cargo-subspace.toml`.
+    #[arg(long, global = true, env = "CARGO_SUBSPACE_TARGET_DIR")]
+    pub target_dir: Option<Utf8PathBuf>,
+
     /// The location where log files will be stored.
     ///
     /// Default: $HOME/.local/state/cargo-subspace/cargo-subspace.log
@@ -34,19 +63,45 @@ pub enum SubspaceCommand {
     /// Print the cargo-subspace version and sysroot path and exit
     Version,
     Discover {
-        /// Activate all features in the workspace.
-        ///
-        /// Note that this flag applies to the whole workspace, not just the crate you're currently
-        /// working on.
-        #[arg(long, conflicts_with = "no_default_features")]
-        all_features: bool,
+        #[command(flatten)]
+        features: FeatureArgs,
+
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
 
-        /// Don't include default features during the workspace discovery process.
+        /// Treat workspace members that are more than this many dependency edges away from the
+        /// crate you're currently working on as if they were not workspace members.
         ///
-        /// Note that this flag applies to the whole workspace, not just the crate you're currently
-        /// working on.
-        #[arg(long, conflicts_with = "all_features")]
-        no_default_features: bool,
+        /// rust-analyzer assumes that crates outside of the workspace don't change, so it can avoid
+        /// watching and re-indexing them. Overrides `member-depth` in `cargo-subspace.toml`.
+        #[arg(long, env = "CARGO_SUBSPACE_MEMBER_DEPTH")]
+        member_depth: Option<usize>,
+
+        /// The name of a crate group, as defined in `cargo-subspace.toml`. The crates in the group
+        /// (and their dependencies) are loaded alongside the crate you're currently working on.
+        #[arg(long, env = "CARGO_SUBSPACE_GROUP")]
+        group: Option<String>,
+
+        /// Load the whole workspace instead of just the crate you're currently working on and its
+        /// dependencies. Useful before large cross-cutting changes, like renames.
+        #[arg(long, env = "CARGO_SUBSPACE_FULL")]
+        full: bool,
+
+        /// The directory containing the source code of the standard library (i.e. the directory
+        /// with the `std` and `core` crates). Defaults to the `rust-src` component of the
+        /// toolchain. Overrides `sysroot-src` in `cargo-subspace.toml`.
+        #[arg(long, env = "CARGO_SUBSPACE_SYSROOT_SRC")]
+        sysroot_src: Option<Utf8PathBuf>,
+
+        /// Emit the project as soon as the metadata has been fetched, using the proc macros and
+        /// build scripts built by earlier discoveries. They're rebuilt in a background process, to
+        /// be used by the next discovery. Overrides `background-build` in `cargo-subspace.toml`.
+        #[arg(long, env = "CARGO_SUBSPACE_BACKGROUND_BUILD")]
+        background_build: bool,
+
+        /// Set for the background process started by `--background-build`
+        #[arg(long, hide = true, env = "CARGO_SUBSPACE_WARM")]
+        warm: bool,
 
         #[cfg(not(target_os = "windows"))]
         /// Profiles the discover process and writes a flamegraph to the given path
@@ -71,13 +126,16 @@ pub struct CheckArgs {
     ///
     /// `cargo-subspace` will find the manifest path of the source file and run `cargo check` with
     /// `--manifest-path` to limit the checks to that file's crate (and its dependencies) only.
-    pub path: FilePathBuf,
+    pub path: Utf8PathBuf,
 
     /// Disables the emission of ANSI color codes in diagnostic output. Useful if your editor
     /// doesn't correctly render ANSI color codes.
     #[arg(long)]
     pub disable_color_diagnostics: bool,
 
+    #[command(flatten)]
+    pub features: FeatureArgs,
+
     /// Extra arguments to be passed through, unchanged, to `cargo check`.
     ///
     /// Example: `cargo-subspace check "/path/to/file.rs" -- --target-dir=/path/to/target`
@@ -85,11 +143,279 @@ pub struct CheckArgs {
     pub passthrough_args: Vec<String>,
 }
 
+// The features to build with. These options are shared by `discover` and `check`, so that the
+// diagnostics from `check` match the cfgs that rust-analyzer sees.
+#[derive(PartialEq, Clone, Debug, Default, Args)]
+pub struct FeatureArgs {
+    /// Activate all features of the crate you're currently working on (and of any crate group
+    /// that is loaded alongside it).
+    #[arg(long, conflicts_with = "no_default_features")]
+    pub all_features: bool,
+
+    /// Don't include the default features of the crate you're currently working on (and of any
+    /// crate group that is loaded alongside it).
+    #[arg(long, conflicts_with = "all_features")]
+    pub no_default_features: bool,
+
+    /// A comma-separated list of package-qualified features to activate, e.g.
+    /// `my-crate/foo,other/bar`. Can be combined with `--no-default-features`. Features are only
+    /// activated for the crate you're currently working on and its direct dependencies. Added to
+    /// `package-features` in `cargo-subspace.toml`.
+    #[arg(long, value_delimiter = ',')]
+    pub features: Vec<String>,
+}
+
+impl FeatureArgs {
+    /// Combines these options with the feature options in the config
+    pub fn with_config(mut self, config: &Config) -> Result<Self> {
+        let mut features = config.qualified_features();
+        features.append(&mut self.features);
+        if let Some(feature) = features.iter().find(|feature| !feature.contains('/')) {
+            anyhow::bail!(
+                "Feature `{feature}` must be qualified with a package name, e.g. \
+                 `my-crate/{feature}`"
+            );
+        }
+
+        self.features = features;
+        self.all_features |= config.all_features;
+        self.no_default_features |= config.no_default_features;
+
+        if self.all_features && self.no_default_features {
+            anyhow::bail!("`all-features` and `no-default-features` cannot be used together");
+        }
+
+        Ok(self)
+    }
+
+    /// Returns the feature arguments for a cargo command that selects the packages with the given
+    /// manifests. Cargo only accepts features of the selected packages and of their direct
+    /// dependencies, so features of any other packages are left out.
+    pub fn cargo_args(&self, manifest_paths: &[&Utf8Path]) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.all_features {
+            args.push("--all-features".into());
+        }
+
+        if self.no_default_features {
+            args.push("--no-default-features".into());
+        }
+
+        if self.features.is_empty() {
+            return args;
+        }
+
+        let applicable: HashSet<String> = manifest_paths
+            .iter()
+            .flat_map(|path| feature_prefixes(path))
+            .collect();
+        let features: Vec<&str> = self
+            .features
+            .iter()
+            .map(String::as_str)
+            .filter(|feature| {
+                let applies = feature
+                    .split_once('/')
+                    .is_some_and(|(package, _)| applicable.contains(package));
+                if !applies {
+                    debug!(feature, "skipping feature of unrelated package");
+                }
+
+                applies
+            })
+            .collect();
+
+        if !features.is_empty() {
+            args.push("--features".into());
+            args.push(features.join(","));
+        }
+
+        args
+    }
+}
+
+/// Returns the prefixes that cargo accepts on features when the package with the given manifest is
+/// selected: the package's own name, and the names of its dependencies as they appear in the
+/// manifest (which, for renamed dependencies, differ from the names of the packages)
+fn feature_prefixes(manifest_path: &Utf8Path) -> Vec<String> {
+    let manifest = match fs::read_to_string(manifest_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(toml::from_str::<toml::Table>(&contents)?))
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warn!(%manifest_path, "failed to read manifest: {e:#}");
+            return Vec::new();
+        }
+    };
+
+    const DEPENDENCY_TABLES: [&str; 5] = [
+        "dependencies",
+        "dev-dependencies",
+        "dev_dependencies",
+        "build-dependencies",
+        "build_dependencies",
+    ];
+    let dependency_names = |table: &toml::Table| -> Vec<String> {
+        DEPENDENCY_TABLES
+            .iter()
+            .filter_map(|key| table.get(*key).and_then(toml::Value::as_table))
+            .flat_map(|deps| deps.keys().cloned())
+            .collect()
+    };
+
+    let package_name = manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(toml::Value::as_str)
+        .map(String::from);
+    let target_dependency_names = manifest
+        .get("target")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|targets| targets.values())
+        .filter_map(toml::Value::as_table)
+        .flat_map(dependency_names);
+
+    package_name
+        .into_iter()
+        .chain(dependency_names(&manifest))
+        .chain(target_dependency_names)
+        .collect()
+}
+
+// Options that control cargo's access to the network. These are passed to every cargo command.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Args)]
+pub struct NetworkArgs {
+    /// Run cargo without accessing the network, using only crates that are already downloaded.
+    #[arg(long, global = true, env = "CARGO_NET_OFFLINE")]
+    pub offline: bool,
+
+    /// Require `Cargo.lock` to be up to date.
+    #[arg(long, global = true)]
+    pub locked: bool,
+
+    /// Equivalent to both `--locked` and `--offline`.
+    #[arg(long, global = true)]
+    pub frozen: bool,
+}
+
+impl NetworkArgs {
+    /// Combines these options with the network options in the config
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.offline |= config.offline;
+        self.locked |= config.locked;
+        self.frozen |= config.frozen;
+        self
+    }
+
+    /// Returns the network arguments for a cargo command
+    pub fn cargo_args(&self) -> Vec<String> {
+        [
+            (self.offline, "--offline"),
+            (self.locked, "--locked"),
+            (self.frozen, "--frozen"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, arg)| arg.to_string())
+        .collect()
+    }
+}
+
+// Timeouts for the steps of `discover`, in seconds. Without them, a step that hangs (e.g. a build
+// script waiting on a service that isn't running) means that `discover` never finishes.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Args)]
+pub struct TimeoutArgs {
+    /// The timeout for `cargo metadata` (and for resolving features with `cargo tree`). Overrides
+    /// `metadata-timeout` in `cargo-subspace.toml`.
+    #[arg(long, env = "CARGO_SUBSPACE_METADATA_TIMEOUT")]
+    pub metadata_timeout: Option<u64>,
+
+    /// The timeout for querying the toolchain's sysroot (and for resolving the toolchain with
+    /// rustup, which may install it). Overrides `sysroot-timeout` in `cargo-subspace.toml`.
+    #[arg(long, env = "CARGO_SUBSPACE_SYSROOT_TIMEOUT")]
+    pub sysroot_timeout: Option<u64>,
+
+    /// The timeout for building proc macros and build scripts. When it elapses, the build is
+    /// stopped, and the project is emitted with the proc macros and build scripts that were
+    /// finished. Overrides `build-timeout` in `cargo-subspace.toml`.
+    #[arg(long, env = "CARGO_SUBSPACE_BUILD_TIMEOUT")]
+    pub build_timeout: Option<u64>,
+}
+
+impl TimeoutArgs {
+    /// Combines these options with the timeouts in the config
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.metadata_timeout = self.metadata_timeout.or(config.metadata_timeout);
+        self.sysroot_timeout = self.sysroot_timeout.or(config.sysroot_timeout);
+        self.build_timeout = self.build_timeout.or(config.build_timeout);
+        self
+    }
+
+    pub fn metadata(&self) -> Option<Duration> {
+        self.metadata_timeout.map(Duration::from_secs)
+    }
+
+    pub fn sysroot(&self) -> Option<Duration> {
+        self.sysroot_timeout.map(Duration::from_secs)
+    }
+
+    pub fn build(&self) -> Option<Duration> {
+        self.build_timeout.map(Duration::from_secs)
+    }
+}
+
+// Limits on the resources used by the builds that `discover` and `check` run, so that they don't
+// starve the editor and other tools.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Args)]
+pub struct ResourceArgs {
+    /// The maximum number of parallel jobs for builds. Overrides `jobs` in `cargo-subspace.toml`.
+    #[arg(long, short, global = true, env = "CARGO_SUBSPACE_JOBS")]
+    pub jobs: Option<u32>,
+
+    /// Run builds with the given niceness (e.g. `10`), which lowers their CPU priority. Overrides
+    /// `nice` in `cargo-subspace.toml`.
+    #[arg(long, global = true, env = "CARGO_SUBSPACE_NICE")]
+    pub nice: Option<i32>,
+
+    /// Run builds in the idle I/O scheduling class, so that they only access the disk when nothing
+    /// else needs it. Requires `ionice`.
+    #[arg(long, global = true, env = "CARGO_SUBSPACE_IONICE")]
+    pub ionice: bool,
+}
+
+impl ResourceArgs {
+    /// Combines these options with the resource options in the config
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.jobs = self.jobs.or(config.jobs);
+        self.nice = self.nice.or(config.nice);
+        self.ionice |= config.ionice;
+        self
+    }
+
+    /// Returns the arguments that limit the parallelism of a cargo build
+    pub fn cargo_args(&self) -> Vec<String> {
+        match self.jobs {
+            Some(jobs) => vec!["--jobs".into(), jobs.to_string()],
+            None => Vec::new(),
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum DiscoverArgument {
     Path(Utf8PathBuf),
-    Buildfile(FilePathBuf),
+    Buildfile(Utf8PathBuf),
+}
+
+impl DiscoverArgument {
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Self::Path(path) | Self::Buildfile(path) => path,
+        }
+    }
 }
 
 impl FromStr for DiscoverArgument {
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::{env, fs};
+
+use anyhow::{Context, Result, anyhow};
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use glob::Pattern;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::util::PathMapping;
+
+pub const CONFIG_FILE_NAME: &str = "cargo-subspace.toml";
+
+/// Configuration read from a `cargo-subspace.toml` file.
+///
+/// The file is found by walking up the directory tree from the manifest being discovered, so it
+/// typically lives next to the workspace's root `Cargo.toml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Config {
+    /// Packages that are kept in the crate graph, but whose dependencies are not followed when
+    /// the graph is pruned.
+    pub transparent_packages: Vec<String>,
+
+    /// Whether to automatically treat packages with a `[package.metadata.hakari]` table (i.e.
+    /// `workspace-hack` crates managed by cargo-hakari) as transparent.
+    pub detect_workspace_hack: bool,
+
+    /// The maximum number of dependency edges between the crate being discovered and a workspace
+    /// member for that member to be treated as part of the workspace. Members further away are
+    /// treated like third party crates, which rust-analyzer assumes do not change.
+    pub member_depth: Option<usize>,
+
+    /// Named groups of packages that can be selected with the `--group` flag. The packages in the
+    /// active group (and their dependencies) are loaded alongside the crate being discovered.
+    pub groups: BTreeMap<String, PackageSelector>,
+
+    /// Packages that are loaded (along with their dependencies) no matter which crate is being
+    /// discovered.
+    pub always_load: PackageSelector,
+
+    /// Packages that are never loaded unless they own the file being discovered. Dependencies
+    /// that are only reachable through these packages are not loaded either.
+    pub never_load: PackageSelector,
+
+    /// Whether to skip pruning and load the entire workspace.
+    pub full: bool,
+
+    /// If the pruned graph would contain more than this percentage of the workspace's members,
+    /// the entire workspace is loaded instead.
+    pub full_threshold: Option<f64>,
+
+    /// Mappings between the paths that cargo sees and the paths that the editor sees, e.g. when
+    /// cargo runs inside of a container.
+    pub path_mappings: Vec<PathPrefixMapping>,
+
+    /// The source code of the standard library to use instead of the `rust-src` component of the
+    /// toolchain, e.g. a shared read-only copy.
+    pub sysroot_src: Option<Utf8PathBuf>,
+
+    /// Whether to activate all features of the crates being discovered.
+    pub all_features: bool,
+
+    /// Whether to deactivate the default features of the crates being discovered.
+    pub no_default_features: bool,
+
+    /// A dedicated target directory for the builds run by `cargo-subspace`, so that they don't
+    /// contend with (or invalidate) the builds you run yourself. Relative paths are relative to
+    /// the directory containing the config file.
+    pub target_dir: Option<Utf8PathBuf>,
+
+    /// Whether to run cargo without accessing the network.
+    pub offline: bool,
+
+    /// Whether to require `Cargo.lock` to be up to date.
+    pub locked: bool,
+
+    /// Whether to require `Cargo.lock` to be up to date and run cargo without accessing the
+    /// network.
+    pub frozen: bool,
+
+    /// Features to activate for individual packages, keyed by package name. They only apply when
+    /// the package is being discovered or is a direct dependency of the package being discovered.
+    pub package_features: BTreeMap<String, Vec<String>>,
+
+    /// The maximum number of parallel jobs for the builds run by `cargo-subspace`.
+    pub jobs: Option<u32>,
+
+    /// The niceness to run builds with, which lowers their CPU priority.
+    pub nice: Option<i32>,
+
+    /// Whether to run builds in the idle I/O scheduling class.
+    pub ionice: bool,
+
+    /// Whether to emit the project before building proc macros and build scripts, and build them
+    /// in a background process for the next discovery instead of emitting the project again.
+    pub background_build: bool,
+
+    /// The timeout for `cargo metadata`, in seconds.
+    pub metadata_timeout: Option<u64>,
+
+    /// The timeout for querying the toolchain's sysroot, in seconds.
+    pub sysroot_timeout: Option<u64>,
+
+    /// The timeout for building proc macros and build scripts, in seconds.
+    pub build_timeout: Option<u64>,
+}
+
+impl Config {
+    /// Loads the config at the given path if one is given. Otherwise, searches the ancestors of
+    /// the given path (and then those of the current directory) for a config file, falling back to
+    /// the default config if none is found.
+    pub fn load(path: Option<&Utf8Path>, start: &Utf8Path) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Self::find(start),
+        };
+
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        debug!(config_path = %path);
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file `{path}`"))?;
+
+        let mut config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file `{path}`"))?;
+
+        // Relative paths are relative to the directory containing the config file
+        if let Some(dir) = path.parent() {
+            config.target_dir = config.target_dir.map(|target_dir| dir.join(target_dir));
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the group with the given name
+    pub fn group(&self, name: &str) -> Result<&PackageSelector> {
+        self.groups
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown crate group `{name}`"))
+    }
+
+    /// Returns the package-qualified features (e.g. `my-crate/foo`) from `package-features`
+    pub fn qualified_features(&self) -> Vec<String> {
+        self.package_features
+            .iter()
+            .flat_map(|(package, features)| {
+                features
+                    .iter()
+                    .map(move |feature| format!("{package}/{feature}"))
+            })
+            .collect()
+    }
+
+    /// Returns the mappings from the paths that the editor sees to the paths that cargo sees
+    pub fn input_mappings(&self) -> Vec<PathMapping> {
+        self.path_mappings
+            .iter()
+            .map(|mapping| PathMapping {
+                from: mapping.editor.clone(),
+                to: mapping.cargo.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the mappings from the paths that cargo sees to the paths that the editor sees
+    pub fn output_mappings(&self) -> Vec<PathMapping> {
+        self.path_mappings
+            .iter()
+            .map(|mapping| PathMapping {
+                from: mapping.cargo.clone(),
+                to: mapping.editor.clone(),
+            })
+            .collect()
+    }
+
+    fn find(start: &Utf8Path) -> Option<Utf8PathBuf> {
+        let cwd = env::current_dir()
+            .ok()
+            .and_then(|dir| Utf8PathBuf::from_path_buf(dir).ok());
+
+        start
+            .ancestors()
+            .chain(cwd.iter().flat_map(|dir| dir.ancestors()))
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+}
+
+/// A mapping between a path prefix that cargo sees and the corresponding prefix that the editor
+/// sees.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PathPrefixMapping {
+    pub cargo: Utf8PathBuf,
+    pub editor: Utf8PathBuf,
+}
+
+/// A set of workspace members, selected by name or by location.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct PackageSelector {
+    /// Glob patterns matched against package names, e.g. `billing-*`
+    pub packages: Vec<String>,
+
+    /// Glob patterns matched against the directories containing package manifests, relative to
+    /// the workspace root, e.g. `services/billing/*`
+    pub paths: Vec<String>,
+}
+
+impl PackageSelector {
+    pub fn matcher(&self) -> Result<PackageMatcher> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| Pattern::new(p).with_context(|| format!("Invalid glob pattern `{p}`")))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(PackageMatcher {
+            packages: compile(&self.packages)?,
+            paths: compile(&self.paths)?,
+        })
+    }
+}
+
+/// The compiled form of a [`PackageSelector`]
+pub struct PackageMatcher {
+    packages: Vec<Pattern>,
+    paths: Vec<Pattern>,
+}
+
+impl PackageMatcher {
+    /// Returns true if a package with the given name and directory (relative to the workspace
+    /// root, if the package lives inside of it) is selected
+    pub fn matches(&self, name: &str, relative_dir: Option<&Utf8Path>) -> bool {
+        self.packages.iter().any(|p| p.matches(name))
+            || relative_dir.is_some_and(|dir| self.paths.iter().any(|p| p.matches(dir.as_str())))
+    }
+}
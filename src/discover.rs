@@ -1,20 +1,39 @@
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env, fs,
+    hash::{DefaultHasher, Hash, Hasher},
     io::{BufRead, BufReader},
     process::Stdio,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::Result;
-use cargo_metadata::{Artifact, Message, Metadata, MetadataCommand, camino::Utf8PathBuf};
+use anyhow::{Result, anyhow};
+use cargo_metadata::{
+    Artifact, CargoOpt, Message, Metadata, MetadataCommand, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+use tracing::{debug, warn};
 
 use crate::{
-    graph::CrateGraph,
-    util::{self, FilePathBuf, Toolchain},
+    cargo_config::CargoConfig,
+    children::{self, Deadline},
+    cli::{FeatureArgs, TimeoutArgs},
+    config::Config,
+    graph::{CrateGraph, ExcludedDependency, PackageNode},
+    session::{BackgroundBuildLock, CachedGraph, DiscoverLock, Session},
+    util::{self, FilePathBuf, Progress, Toolchain},
 };
 
 pub struct DiscoverRunner {
     toolchain: Toolchain,
-    features: FeatureOption,
+    features: FeatureArgs,
+    timeouts: TimeoutArgs,
     manifest_path: FilePathBuf,
+    config: Config,
+    group: Option<String>,
+    build: bool,
 }
 
 impl DiscoverRunner {
@@ -22,100 +41,684 @@ impl DiscoverRunner {
         Self {
             manifest_path,
             toolchain,
-            features: FeatureOption::Default,
+            features: FeatureArgs::default(),
+            timeouts: TimeoutArgs::default(),
+            config: Config::default(),
+            group: None,
+            build: true,
         }
     }
 
-    pub fn with_all_features(mut self) -> Self {
-        self.features = FeatureOption::All;
+    /// Loads the packages in the crate group with the given name (as defined in the config)
+    /// alongside the crate being discovered
+    pub fn with_group(mut self, group: Option<String>) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Uses the given feature options (already combined with the config) for every cargo command
+    pub fn with_features(mut self, features: FeatureArgs) -> Self {
+        self.features = features;
         self
     }
 
-    pub fn with_no_default_features(mut self) -> Self {
-        self.features = FeatureOption::NoDefault;
+    /// Whether to build the proc macros and build scripts. If they aren't built, the ones built by
+    /// earlier discoveries are used instead.
+    pub fn with_build(mut self, build: bool) -> Self {
+        self.build = build;
         self
     }
 
-    pub fn with_default_features(mut self) -> Self {
-        self.features = FeatureOption::Default;
+    /// Uses the given timeouts (already combined with the config) for `cargo metadata` and the
+    /// build
+    pub fn with_timeouts(mut self, timeouts: TimeoutArgs) -> Self {
+        self.timeouts = timeouts;
         self
     }
 
     /// Fetches the cargo metadata, constructs a crate graph, and prunes the graph such that it
-    /// only contains dependencies of the crate for the given manifest path
+    /// only contains dependencies of the crate for the given manifest path. If the manifest path
+    /// points to a virtual manifest, the crates to load are chosen as described in
+    /// `DiscoverRunner::virtual_manifest_packages`.
     pub fn run(self) -> Result<CrateGraph> {
-        // Get the cargo workspace metadata
-        let metadata = self.get_metadata()?;
+        let target_triple = self.toolchain.host_triple()?;
+        let (mut graph, default_members) = self.load_workspace(target_triple.as_deref())?;
+
+        let target_cfgs = self
+            .toolchain
+            .target_cfgs()
+            .inspect_err(|e| warn!("Failed to query the target's cfgs: {e:#}"))
+            .unwrap_or_default();
+        graph.cargo_config = CargoConfig::load(
+            self.cargo_dir(),
+            self.toolchain.cargo_home().as_deref(),
+            target_triple.as_deref(),
+            &target_cfgs,
+        );
+        graph.mark_transparent(
+            &self.config.transparent_packages,
+            self.config.detect_workspace_hack,
+        );
+
+        self.discover(graph, &default_members)
+    }
+
+    /// Takes the lock on building the proc macros and build scripts of the workspace in the
+    /// background, or returns `None` if another background build holds it
+    pub fn lock_background_build(&self) -> Result<Option<BackgroundBuildLock>> {
+        BackgroundBuildLock::try_acquire(&self.lock_directory())
+    }
+
+    /// Fetches the workspace's metadata and lowers it into a crate graph (along with the explicit
+    /// default members of the workspace), which hasn't been pruned yet.
+    ///
+    /// Concurrent discoveries of the same workspace wait for each other to fetch the metadata. If a
+    /// discovery with the same inputs fetched it while we waited, its graph is reused, even if it
+    /// discovered a different crate. Pruning and building aren't serialized, since cargo's build
+    /// lock already serializes the builds.
+    fn load_workspace(&self, target_triple: Option<&str>) -> Result<(CrateGraph, Vec<PackageId>)> {
+        let waiting_since = SystemTime::now();
+        let lock_directory = self.lock_directory();
+        let key = self.cache_key(target_triple);
+
+        let _lock = DiscoverLock::acquire(&lock_directory)
+            .inspect_err(|e| warn!("{e:#}"))
+            .ok();
+
+        if let Some(cached) = CachedGraph::load(&lock_directory, &key, waiting_since)
+            && self.owning_package(&cached.graph).is_ok()
+        {
+            util::log_progress("Reusing the metadata fetched by a concurrent discovery")?;
+
+            return Ok((cached.graph, cached.default_members));
+        }
+
+        let started_at = SystemTime::now();
+        let metadata = self.get_metadata(target_triple)?;
+        let default_members = explicit_default_members(&metadata)?;
 
         // Lower the metadata into our internal crate graph representation
-        let mut graph = CrateGraph::from_metadata(metadata)?;
+        let graph = CrateGraph::from_metadata(metadata)?;
+        let cached = CachedGraph::new(key, started_at, graph, default_members);
+        if let Err(e) = cached.save(&lock_directory) {
+            warn!("{e:#}");
+        }
+
+        Ok((cached.graph, cached.default_members))
+    }
+
+    fn discover(&self, mut graph: CrateGraph, default_members: &[PackageId]) -> Result<CrateGraph> {
+        // The outputs of packages that are no longer in the workspace (e.g. after a dependency was
+        // updated) are forgotten
+        let mut session = Session::load(&graph.target_directory);
+        session.retain_outputs(&graph);
 
         // Prune the graph such that the remaining nodes are only those reachable from the node
-        // with the given manifest path
-        graph.prune(self.manifest_path.as_file_path())?;
+        // with the given manifest path (and any other packages that have been configured to be
+        // loaded)
+        let owner = self.owning_package(&graph)?;
+        let mut roots = match owner.clone() {
+            Some(id) => vec![id],
+            None => self.virtual_manifest_packages(&graph, default_members, &session)?,
+        };
+        if let Some(group) = self.group.as_deref() {
+            let members = graph.select(self.config.group(group)?)?;
+            if members.is_empty() {
+                util::log_progress(format!("Crate group `{group}` did not match any packages"))?;
+            }
+
+            roots.extend(members);
+        }
+
+        if roots.is_empty() {
+            anyhow::bail!(
+                "`{}` is a virtual manifest, and there are no default members, crate group, or \
+                 previously discovered crates to fall back to",
+                self.manifest_path
+            );
+        }
+
+        roots.extend(graph.select(&self.config.always_load)?);
+        roots.sort();
+        roots.dedup();
+
+        let excluded = graph
+            .select(&self.config.never_load)?
+            .into_iter()
+            .filter(|id| !roots.contains(id))
+            .collect();
+
+        let full = self.config.full || {
+            let coverage = graph.workspace_coverage(&graph.reachable(&roots, &excluded));
+            debug!(coverage, "workspace coverage of pruned graph");
+
+            self.config
+                .full_threshold
+                .is_some_and(|threshold| coverage > threshold)
+        };
+
+        if full {
+            // Skip pruning and load the entire workspace
+            util::log_progress("Loading the whole workspace")?;
+        } else {
+            let excluded_dependencies = graph.prune(&roots, &excluded);
+            self.warn_excluded_dependencies(excluded_dependencies)?;
+
+            // The metadata unifies features across the whole workspace, but the crates being
+            // discovered are built on their own
+            if let Err(e) = self.resolve_package_features(&mut graph, &roots) {
+                warn!("{e:#}");
+                util::log_progress(
+                    "Warning: failed to resolve features; using workspace-wide features",
+                )?;
+            }
+        }
+
+        if let Some(depth) = self.config.member_depth {
+            graph.limit_member_depth(&roots, depth);
+        }
 
-        // Build the compile time dependencies (proc macros & build scripts) for the pruned graph
-        self.build_compile_time_dependencies(&mut graph)?;
+        // The proc macros and build scripts from earlier discoveries are usually still up to date,
+        // so they're used instead of building them
+        if !self.build {
+            session.restore_outputs(&mut graph);
+        }
+
+        // Build the compile time dependencies (proc macros & build scripts) for the graph
+        if self.build {
+            let members: Vec<PackageId>;
+            let packages = if full {
+                members = graph
+                    .inner
+                    .iter()
+                    .filter(|(_, pkg)| pkg.is_workspace_member)
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                &members
+            } else {
+                &roots
+            };
+            self.build_compile_time_dependencies(&mut graph, packages)?;
+        }
+
+        // Only the crate that was actually requested is remembered. Remembering everything that
+        // was loaded would make the virtual manifest fallback grow with every discovery
+        let mut session = Session {
+            roots: owner.map(|id| vec![id]).unwrap_or(session.roots),
+            package_dirs: graph
+                .inner
+                .values()
+                .filter_map(|pkg| pkg.manifest_path.parent().map(|dir| dir.to_path_buf()))
+                .collect(),
+            sysroot_src: session.sysroot_src,
+            proc_macro_dylibs: session.proc_macro_dylibs,
+            build_scripts: session.build_scripts,
+        };
+        session.record_outputs(&graph);
+
+        if let Err(e) = session.save(&graph.target_directory) {
+            warn!("{e:#}");
+        }
 
         Ok(graph)
     }
 
-    fn get_metadata(&self) -> Result<Metadata> {
-        util::log_progress("Fetching metadata")?;
+    /// Returns the package that owns the manifest being discovered, or `None` if the manifest is
+    /// the workspace's virtual manifest
+    fn owning_package(&self, graph: &CrateGraph) -> Result<Option<PackageId>> {
+        match graph.find_package(self.manifest_path.as_file_path()) {
+            Ok(id) => Ok(Some(id.clone())),
+            Err(_) if self.is_root_manifest(graph) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-        let rustc_info = String::from_utf8(self.toolchain.rustc().arg("-vV").output()?.stdout)?;
-        let mut cmd = MetadataCommand::new();
-        cmd.manifest_path(self.manifest_path.as_std_path());
+    /// Returns true if the manifest being discovered is the workspace's root manifest. Both paths
+    /// are canonicalized, since either one may point to the workspace through a symlink.
+    fn is_root_manifest(&self, graph: &CrateGraph) -> bool {
+        let root_manifest = graph.workspace_root.join("Cargo.toml");
 
-        if let Some(cargo_home) = self.toolchain.cargo_home.as_ref() {
-            cmd.cargo_path(cargo_home.join("bin/cargo"));
+        match (
+            self.manifest_path.canonicalize_utf8(),
+            root_manifest.canonicalize_utf8(),
+        ) {
+            (Ok(manifest_path), Ok(root_manifest)) => manifest_path == root_manifest,
+            _ => false,
         }
+    }
 
-        let target_triple = rustc_info
-            .lines()
-            .find_map(|line| line.strip_prefix("host: "));
+    /// Returns the packages to load when discovering the workspace's virtual manifest, which isn't
+    /// owned by any package.
+    ///
+    /// If a crate group is active, no packages are returned (the group's members are loaded
+    /// instead). Otherwise, falls back to the workspace's default members, or to the crate that was
+    /// discovered the last time discovery was run for this workspace.
+    fn virtual_manifest_packages(
+        &self,
+        graph: &CrateGraph,
+        default_members: &[PackageId],
+        session: &Session,
+    ) -> Result<Vec<PackageId>> {
+        if let Some(group) = self.group.as_deref() {
+            util::log_progress(format!(
+                "Discovering a virtual manifest; loading crate group `{group}`"
+            ))?;
+
+            return Ok(Vec::new());
+        }
+
+        if !default_members.is_empty() {
+            util::log_progress("Discovering a virtual manifest; loading default members")?;
+
+            return Ok(default_members.to_vec());
+        }
+
+        let previous_roots: Vec<PackageId> = session
+            .roots
+            .iter()
+            .filter(|id| graph.inner.contains_key(id))
+            .cloned()
+            .collect();
+
+        if !previous_roots.is_empty() {
+            util::log_progress(
+                "Discovering a virtual manifest; loading previously discovered crates",
+            )?;
+        }
+
+        Ok(previous_roots)
+    }
+
+    fn warn_excluded_dependencies(
+        &self,
+        excluded_dependencies: Vec<ExcludedDependency>,
+    ) -> Result<()> {
+        let mut dependents: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for ExcludedDependency {
+            dependent,
+            dependency,
+        } in excluded_dependencies
+        {
+            dependents.entry(dependency).or_default().insert(dependent);
+        }
+
+        for (dependency, dependents) in dependents {
+            let dependents = dependents.into_iter().collect::<Vec<_>>().join(", ");
+            warn!(dependency, dependents, "excluded package is a dependency");
+            util::log_progress(format!(
+                "Warning: `{dependency}` is excluded by `never-load`, but is a dependency of: \
+                 {dependents}"
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the features of the packages in the graph with the features cargo enables when
+    /// building just the given packages, which can differ from the features that are unified
+    /// across the whole workspace.
+    ///
+    /// Like the build in [`DiscoverRunner::build_compile_time_dependencies`], the features include
+    /// the dev-dependencies of the given packages (but not of their dependencies), as if their
+    /// tests were built.
+    fn resolve_package_features(&self, graph: &mut CrateGraph, roots: &[PackageId]) -> Result<()> {
+        util::log_progress("Resolving features")?;
+
+        let mut cmd = self.toolchain.cargo();
+        cmd.arg("tree")
+            .arg("--manifest-path")
+            .arg(self.manifest_path.as_std_path())
+            .arg("--prefix")
+            .arg("none")
+            .arg("--format")
+            .arg("{f}|{p}")
+            .args(self.package_args(graph, Some(roots)))
+            .args(self.toolchain.network_args())
+            .current_dir(self.cargo_dir());
+
+        let output = children::output(&mut cmd, self.timeouts.metadata())?;
+        if !output.status.success() {
+            return Err(util::cargo_error(
+                "tree",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let resolved = parse_tree_features(&stdout);
+        for pkg in graph.inner.values_mut() {
+            let version = pkg.version.to_string();
+            if let Some(features) = resolved.get(&(pkg.name.as_str(), version.as_str())) {
+                pkg.features = features.iter().map(|feature| feature.to_string()).collect();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the arguments that select the given packages (or the whole workspace, if no
+    /// packages are given) and their features for a cargo command
+    fn package_args(&self, graph: &CrateGraph, packages: Option<&[PackageId]>) -> Vec<String> {
+        let mut args = Vec::new();
+        let packages: Vec<&PackageNode> = match packages {
+            // The packages are selected explicitly, since they may not include the package with
+            // the given manifest path (e.g. if it's a virtual manifest), or there may be more than
+            // one of them (e.g. if a crate group is active)
+            Some(packages) => {
+                let packages: Vec<&PackageNode> = packages
+                    .iter()
+                    .filter_map(|id| graph.inner.get(id))
+                    .collect();
+                for pkg in packages.iter() {
+                    args.push("--package".into());
+                    args.push(format!("{}@{}", pkg.name, pkg.version));
+                }
+
+                packages
+            }
+            None => {
+                args.push("--workspace".into());
+
+                graph
+                    .inner
+                    .values()
+                    .filter(|pkg| pkg.is_workspace_member)
+                    .collect()
+            }
+        };
+
+        // Cargo rejects feature options when a package outside of the workspace is selected
+        if packages.iter().all(|pkg| pkg.is_workspace_member) {
+            let manifest_paths: Vec<&Utf8Path> =
+                packages.iter().map(|pkg| &*pkg.manifest_path).collect();
+            args.extend(self.features.cargo_args(&manifest_paths));
+        }
+
+        args
+    }
+
+    fn get_metadata(&self, target_triple: Option<&str>) -> Result<Metadata> {
+        util::log_progress("Fetching metadata")?;
+
+        let mut other_options = Vec::new();
         if let Some(target_triple) = target_triple {
-            cmd.other_options(["--filter-platform".into(), target_triple.into()]);
+            other_options.extend(["--filter-platform".into(), target_triple.into()]);
+        }
+
+        let mut cmd = self.toolchain.metadata_command(other_options);
+        cmd.manifest_path(self.manifest_path.as_std_path())
+            .current_dir(self.cargo_dir());
+
+        // `cargo metadata` selects every workspace member, so `--all-features` and
+        // `--no-default-features` apply to all of them. It rejects features of dependencies, though,
+        // so only the features of workspace members are passed
+        if self.features.all_features {
+            cmd.features(CargoOpt::AllFeatures);
         }
 
-        match self.features {
-            FeatureOption::All => {
-                cmd.features(cargo_metadata::CargoOpt::AllFeatures);
+        if self.features.no_default_features {
+            cmd.features(CargoOpt::NoDefaultFeatures);
+        }
+
+        if !self.features.features.is_empty() {
+            let members = self.workspace_member_names()?;
+            let features: Vec<String> = self
+                .features
+                .features
+                .iter()
+                .filter(|feature| {
+                    feature
+                        .split_once('/')
+                        .is_some_and(|(package, _)| members.contains(package))
+                })
+                .cloned()
+                .collect();
+
+            if !features.is_empty() {
+                cmd.features(CargoOpt::SomeFeatures(features));
             }
-            FeatureOption::NoDefault => {
-                cmd.features(cargo_metadata::CargoOpt::NoDefaultFeatures);
+        }
+
+        self.run_metadata(&cmd)
+    }
+
+    /// Returns the names of the workspace's members, without resolving any dependencies
+    fn workspace_member_names(&self) -> Result<HashSet<String>> {
+        let mut cmd = self.toolchain.metadata_command(Vec::new());
+        cmd.manifest_path(self.manifest_path.as_std_path())
+            .current_dir(self.cargo_dir())
+            .no_deps();
+        let metadata = self.run_metadata(&cmd)?;
+
+        Ok(metadata
+            .workspace_packages()
+            .into_iter()
+            .map(|pkg| pkg.name.to_string())
+            .collect())
+    }
+
+    fn run_metadata(&self, cmd: &MetadataCommand) -> Result<Metadata> {
+        let output = children::output(&mut cmd.cargo_command(), self.timeouts.metadata())?;
+        if !output.status.success() {
+            return Err(util::cargo_error(
+                "metadata",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let json = stdout
+            .lines()
+            .find(|line| line.starts_with('{'))
+            .ok_or_else(|| anyhow!("`cargo metadata` did not output any metadata"))?;
+
+        Ok(MetadataCommand::parse(json)?)
+    }
+
+    /// The target directory that holds the discover lock and the graph shared between concurrent
+    /// discoveries. It's needed before the metadata (which reports the real target directory) is
+    /// fetched, so it's resolved the same way cargo resolves it: the configured target directory,
+    /// `CARGO_TARGET_DIR`, `CARGO_BUILD_TARGET_DIR`, `build.target-dir` in cargo's config, or the
+    /// `target` directory in the root of the workspace.
+    fn lock_directory(&self) -> Utf8PathBuf {
+        if let Some(target_dir) = self.config.target_dir.clone() {
+            return target_dir;
+        }
+
+        let from_env = ["CARGO_TARGET_DIR", "CARGO_BUILD_TARGET_DIR"]
+            .into_iter()
+            .find_map(|key| env::var(key).ok().filter(|dir| !dir.is_empty()));
+        if let Some(target_dir) = from_env {
+            return self.cargo_dir().join(target_dir);
+        }
+
+        if let Some(target_dir) =
+            CargoConfig::target_dir(self.cargo_dir(), self.toolchain.cargo_home().as_deref())
+        {
+            return target_dir;
+        }
+
+        let is_workspace_root = |dir: &Utf8Path| {
+            fs::read_to_string(dir.join("Cargo.toml"))
+                .ok()
+                .and_then(|contents| toml::from_str::<toml::Table>(&contents).ok())
+                .is_some_and(|manifest| manifest.contains_key("workspace"))
+        };
+
+        self.cargo_dir()
+            .ancestors()
+            .find(|dir| is_workspace_root(dir))
+            .unwrap_or(self.cargo_dir())
+            .join("target")
+    }
+
+    /// Returns a key that identifies the inputs of `cargo metadata`, so that its graph is only
+    /// reused by discoveries with the same inputs
+    fn cache_key(&self, target_triple: Option<&str>) -> String {
+        let mut hasher = DefaultHasher::new();
+        target_triple.hash(&mut hasher);
+        format!("{:?}", self.features).hash(&mut hasher);
+        self.toolchain.network_args().hash(&mut hasher);
+        self.toolchain.cargo_path().hash(&mut hasher);
+        self.toolchain.rustc_path().hash(&mut hasher);
+
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// The directory cargo runs in, which determines the `.cargo/config.toml` files it uses
+    fn cargo_dir(&self) -> &Utf8Path {
+        self.manifest_path.parent().unwrap_or(&self.manifest_path)
+    }
+
+    /// Builds the proc macros and runs the build scripts that the given packages (and their tests)
+    /// depend on, including the ones of the packages themselves.
+    ///
+    /// Only the packages that have a proc macro or a build script are selected, so the code of the
+    /// crates being discovered is only checked if they have a build script of their own. Note that
+    /// cargo resolves the features of the selected packages as if only they were being built, which
+    /// can differ from the features they're built with as dependencies of the crates being
+    /// discovered.
+    fn build_compile_time_dependencies(
+        &self,
+        graph: &mut CrateGraph,
+        packages: &[PackageId],
+    ) -> Result<()> {
+        let selected = graph.compile_time_packages(packages);
+        if selected.is_empty() {
+            debug!("no proc macros or build scripts to build");
+
+            return Ok(());
+        }
+
+        // Checking a proc macro only produces its metadata, so proc macros are built to get their
+        // dylibs. The other packages are only checked, which still runs their build scripts. Cargo
+        // rejects feature options when a package outside of the workspace is selected, so the
+        // workspace members are built separately
+        let mut steps: Vec<(&str, Vec<PackageId>)> = Vec::new();
+        for subcommand in ["build", "check"] {
+            let (members, others): (Vec<PackageId>, Vec<PackageId>) = selected
+                .iter()
+                .filter(|id| graph.inner[*id].is_proc_macro() == (subcommand == "build"))
+                .cloned()
+                .partition(|id| graph.inner[id].is_workspace_member);
+
+            steps.extend(
+                [members, others]
+                    .into_iter()
+                    .filter(|ids| !ids.is_empty())
+                    .map(|ids| (subcommand, ids)),
+            );
+        }
+
+        // Units that are built more than once (e.g. for both the host and the target, or by more
+        // than one step) are only counted once
+        let mut progress = Progress::start(
+            "Building proc macros and build scripts",
+            graph.compile_time_units(packages),
+        )?;
+        let mut built: HashSet<(PackageId, bool)> = HashSet::new();
+
+        // The timeout applies to all of the steps together
+        let deadline = self
+            .timeouts
+            .build()
+            .map(|timeout| Instant::now() + timeout);
+        for (subcommand, ids) in steps {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let finished =
+                self.run_build_step(graph, subcommand, &ids, timeout, &mut progress, &mut built)?;
+            if !finished {
+                break;
             }
-            FeatureOption::Default => (),
         }
 
-        Ok(cmd.exec()?)
+        progress.finish()
     }
 
-    fn build_compile_time_dependencies(&self, graph: &mut CrateGraph) -> Result<()> {
-        // TODO: check rust version to decide whether to use --compile-time-deps, which allows us to
-        // only build proc macros/build scripts during this step instead of building the whole crate
-        let child = self
-            .toolchain
-            .cargo()
+    /// Runs `cargo <subcommand>` for the given packages, and records the proc macro dylibs and
+    /// build script outputs it produces in the graph. Returns false if it timed out, in which case
+    /// whatever was built before the timeout elapsed is still recorded.
+    fn run_build_step(
+        &self,
+        graph: &mut CrateGraph,
+        subcommand: &str,
+        packages: &[PackageId],
+        timeout: Option<Duration>,
+        progress: &mut Progress,
+        built: &mut HashSet<(PackageId, bool)>,
+    ) -> Result<bool> {
+        // TODO: check rust version to decide whether to use --compile-time-deps, which builds only
+        // the proc macros and build scripts of the selected packages' dependencies, too
+        let mut cmd = self.toolchain.cargo_build();
+        cmd
             // .arg("+nightly")
-            .arg("check")
+            .arg(subcommand)
             // .arg("--compile-time-deps")
-            .arg("--quiet")
             .arg("--message-format")
             .arg("json")
             .arg("--keep-going")
-            .arg("--all-targets")
             .arg("--manifest-path")
             .arg(self.manifest_path.as_std_path())
+            .current_dir(self.cargo_dir())
             // .arg("-Zunstable-options")
             // .env("__CARGO_TEST_CHANNEL_OVERRIDE_DO_NOT_USE_THIS", "nightly")
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()?;
+            .stderr(Stdio::piped());
+
+        cmd.args(self.package_args(graph, Some(packages)))
+            .args(self.toolchain.network_args())
+            .args(self.toolchain.build_args());
 
-        for line in BufReader::new(child.stdout.unwrap()).lines() {
+        if let Some(target_dir) = self.config.target_dir.as_ref() {
+            cmd.arg("--target-dir").arg(target_dir);
+        }
+
+        let mut child = children::spawn(&mut cmd)?;
+        let deadline = Deadline::start(&child, timeout);
+
+        // Read stderr on another thread so that cargo can't block on writing to it while we're
+        // reading stdout. It's used to explain failures, and to find out which packages cargo has
+        // started building (e.g. `Compiling foo v0.1.0`)
+        let started: Arc<Mutex<Vec<String>>> = Arc::default();
+        let stderr = {
+            let started = started.clone();
+            let stderr = child.stderr.take().unwrap();
+
+            thread::spawn(move || {
+                let mut output = String::new();
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    let status = line.trim_start();
+                    if let Some(package) = ["Compiling ", "Checking "]
+                        .iter()
+                        .find_map(|prefix| status.strip_prefix(prefix))
+                        && let Ok(mut started) = started.lock()
+                    {
+                        started.push(package_label(package));
+                    }
+
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+
+                output
+            })
+        };
+
+        let mut finished = HashSet::new();
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
             let line = line?;
-            let message = serde_json::from_str::<Message>(&line)?;
+            let message = match serde_json::from_str::<Message>(&line) {
+                Ok(message) => message,
+                // The last message may be cut off if cargo was terminated
+                Err(_) if deadline.expired() => break,
+                Err(e) => return Err(e.into()),
+            };
 
             match message {
                 Message::CompilerArtifact(Artifact {
@@ -124,34 +727,136 @@ impl DiscoverRunner {
                     package_id,
                     ..
                 }) => {
+                    if let Some(pkg) = graph.inner.get(&package_id)
+                        && !target.is_custom_build()
+                    {
+                        finished.insert(format!("{} v{}", pkg.name, pkg.version));
+                    }
+
                     if let Some(dylib) = filenames.into_iter().find(is_dylib)
                         && target.is_proc_macro()
+                        && let Some(pkg) = graph.get_mut(&package_id)
                     {
-                        util::log_progress(format!("proc-macro {} built", target.name))?;
-                        if let Some(pkg) = graph.get_mut(&package_id) {
-                            pkg.proc_macro_dylib = Some(dylib.try_into()?);
+                        pkg.proc_macro_dylib = Some(dylib.try_into()?);
+                        if built.insert((package_id, true)) {
+                            progress.advance(&target.name)?;
                         }
                     }
                 }
                 Message::BuildScriptExecuted(script) => {
                     if let Some(pkg) = graph.get_mut(&script.package_id) {
-                        util::log_progress(format!("build script {} run", pkg.name))?;
+                        let name = pkg.name.clone();
+                        let package_id = script.package_id.clone();
                         pkg.build_script = Some(script);
+                        if built.insert((package_id, false)) {
+                            progress.advance(&name)?;
+                        }
                     }
                 }
                 _ => (),
             }
         }
 
-        Ok(())
+        // Compilation errors are expected (and reported by rust-analyzer), but if cargo couldn't
+        // even get the dependencies, none of the proc macros or build scripts could be built
+        let status = child.wait()?;
+        let stderr = stderr.join().unwrap_or_default();
+
+        // Whatever was built before the timeout elapsed is still used
+        if deadline.expired() {
+            let running: Vec<String> = started
+                .lock()
+                .map(|started| {
+                    started
+                        .iter()
+                        .filter(|package| !finished.contains(*package))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            warn!(?running, "building compile time dependencies timed out");
+            let timeout = self.timeouts.build().unwrap_or_default().as_secs();
+            let message = if running.is_empty() {
+                format!(
+                    "Warning: building proc macros and build scripts timed out after {timeout}s"
+                )
+            } else {
+                format!(
+                    "Warning: building proc macros and build scripts timed out after {timeout}s \
+                     while building: {}",
+                    running.join(", ")
+                )
+            };
+            util::log_progress(message)?;
+
+            return Ok(false);
+        }
+
+        if !status.success() && util::is_network_error(&stderr) {
+            return Err(util::cargo_error(subcommand, &stderr));
+        }
+
+        Ok(true)
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum FeatureOption {
-    NoDefault,
-    All,
-    Default,
+/// Returns the workspace's default members, if they are explicitly declared with
+/// `workspace.default-members` in the workspace's root manifest. Cargo otherwise reports every
+/// member of a virtual workspace as a default member.
+fn explicit_default_members(metadata: &Metadata) -> Result<Vec<PackageId>> {
+    if metadata.workspace_default_members.is_missing() {
+        return Ok(Vec::new());
+    }
+
+    let manifest_path = metadata.workspace_root.join("Cargo.toml");
+    let manifest: toml::Table = toml::from_str(&fs::read_to_string(&manifest_path)?)?;
+    let declared = manifest
+        .get("workspace")
+        .and_then(|workspace| workspace.get("default-members"))
+        .is_some();
+
+    if declared {
+        Ok(metadata.workspace_default_members.to_vec())
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Parses the output of `cargo tree --prefix none --format {f}|{p}` into the features of each
+/// package, keyed by its name and version.
+///
+/// Each line is `<features>|<name> v<version>[ (<source>)][ (*)]`. A package can appear more than
+/// once (e.g. as both a normal and a build dependency), possibly with different features, in which
+/// case rust-analyzer sees the union of them.
+fn parse_tree_features(output: &str) -> HashMap<(&str, &str), BTreeSet<&str>> {
+    let mut resolved: HashMap<(&str, &str), BTreeSet<&str>> = HashMap::new();
+    for line in output.lines() {
+        let Some((features, package)) = line.split_once('|') else {
+            continue;
+        };
+        let mut parts = package.split_whitespace();
+        let (Some(name), Some(version)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+
+        resolved
+            .entry((name, version.trim_start_matches('v')))
+            .or_default()
+            .extend(features.split(',').filter(|feature| !feature.is_empty()));
+    }
+
+    resolved
+}
+
+/// Returns the `<name> v<version>` part of a package in cargo's status output, e.g. `foo v0.1.0` for
+/// `foo v0.1.0 (/path/to/foo)`
+fn package_label(package: &str) -> String {
+    package
+        .split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 fn is_dylib(path: &Utf8PathBuf) -> bool {
@@ -159,3 +864,52 @@ fn is_dylib(path: &Utf8PathBuf) -> bool {
         .map(|ext| ["dylib", "so", "dll"].contains(&ext))
         .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_features_are_merged_per_package() {
+        let output = "\
+|app v0.1.0 (/work/app)
+default,derive,std|serde v1.0.229
+default|serde_derive v1.0.229 (proc-macro)
+proc-macro|proc-macro2 v1.0.107
+std|proc-macro2 v1.0.107 (*)
+|unicode-ident v1.0.27
+
+not a package line
+";
+
+        let resolved = parse_tree_features(output);
+
+        assert_eq!(resolved.len(), 5);
+        assert!(resolved[&("app", "0.1.0")].is_empty());
+        assert_eq!(
+            resolved[&("serde", "1.0.229")],
+            BTreeSet::from(["default", "derive", "std"])
+        );
+        assert_eq!(
+            resolved[&("serde_derive", "1.0.229")],
+            BTreeSet::from(["default"])
+        );
+        assert_eq!(
+            resolved[&("proc-macro2", "1.0.107")],
+            BTreeSet::from(["proc-macro", "std"])
+        );
+    }
+
+    #[test]
+    fn tree_features_distinguish_versions() {
+        let output = "\
+std|syn v1.0.109
+full|syn v2.0.100
+";
+
+        let resolved = parse_tree_features(output);
+
+        assert_eq!(resolved[&("syn", "1.0.109")], BTreeSet::from(["std"]));
+        assert_eq!(resolved[&("syn", "2.0.100")], BTreeSet::from(["full"]));
+    }
+}
@@ -1,15 +1,26 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use anyhow::Result;
-use cargo_metadata::{BuildScript, Edition, Metadata, PackageId, semver::Version};
+use cargo_metadata::{
+    BuildScript, DependencyKind, Edition, Metadata, PackageId, camino::Utf8PathBuf, semver::Version,
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 
 use crate::{
+    cargo_config::CargoConfig,
+    config::PackageSelector,
     rust_project::{BuildInfo, Crate, CrateSource, Dep, TargetKind},
     util::{FilePath, FilePathBuf},
 };
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CrateGraph {
     pub inner: HashMap<PackageId, PackageNode>,
+    pub workspace_root: Utf8PathBuf,
+    pub target_directory: Utf8PathBuf,
+    /// The cargo config that applies to every crate in the graph
+    pub cargo_config: CargoConfig,
 }
 
 impl CrateGraph {
@@ -31,9 +42,18 @@ impl CrateGraph {
                 dependencies
                     .entry(node.id)
                     .or_default()
-                    .extend(node.deps.into_iter().map(|dep| Dependency {
-                        id: dep.pkg,
-                        name: dep.name,
+                    .extend(node.deps.into_iter().map(|dep| {
+                        let is_only = |kind: DependencyKind| {
+                            !dep.dep_kinds.is_empty()
+                                && dep.dep_kinds.iter().all(|info| info.kind == kind)
+                        };
+
+                        Dependency {
+                            is_dev_only: is_only(DependencyKind::Development),
+                            is_build_only: is_only(DependencyKind::Build),
+                            id: dep.pkg,
+                            name: dep.name,
+                        }
                     }));
             }
         }
@@ -61,11 +81,14 @@ impl CrateGraph {
                 .collect::<Result<Vec<_>>>()?;
 
             let node = PackageNode {
+                is_workspace_hack: package.metadata.get("hakari").is_some(),
+                is_transparent: false,
                 name: package.name.to_string(),
                 targets,
                 manifest_path: package.manifest_path.try_into()?,
                 version: package.version,
                 is_workspace_member: workspace_members.contains(&package.id),
+                is_distant_member: false,
                 repository: package.repository,
                 features: features
                     .get(&package.id)
@@ -81,55 +104,289 @@ impl CrateGraph {
             inner.insert(package.id, node);
         }
 
-        Ok(Self { inner })
+        Ok(Self {
+            inner,
+            workspace_root: metadata.workspace_root,
+            target_directory: metadata.target_directory,
+            cargo_config: CargoConfig::default(),
+        })
     }
 
     pub fn get_mut(&mut self, package_id: &PackageId) -> Option<&mut PackageNode> {
         self.inner.get_mut(package_id)
     }
 
+    /// Marks packages as transparent. Transparent packages remain in the graph after pruning, but
+    /// their dependencies are not followed.
+    ///
+    /// A package is transparent if its name is in `names` or, if `detect_workspace_hack` is set,
+    /// if it is a `workspace-hack` crate managed by cargo-hakari.
+    pub fn mark_transparent(&mut self, names: &[String], detect_workspace_hack: bool) {
+        for node in self.inner.values_mut() {
+            node.is_transparent =
+                names.contains(&node.name) || (detect_workspace_hack && node.is_workspace_hack);
+
+            if node.is_transparent {
+                debug!(package = node.name, "marking package as transparent");
+            }
+        }
+    }
+
     /// Prunes the graph such that the remaining nodes consist only of:
-    /// 1. The package with the given manifest path; and
-    /// 2. The dependencies of that package
-    pub fn prune(&mut self, manifest_path: FilePath<'_>) -> Result<()> {
-        let abs = std::path::absolute(manifest_path.as_std_path())?;
-        let Some((id, _)) = self
-            .inner
-            .iter()
-            .find(|(_, node)| node.manifest_path.as_std_path() == abs)
-        else {
-            anyhow::bail!(
-                "Could not find workspace member with manifest path {}",
-                manifest_path.as_ref().display()
-            )
-        };
+    /// 1. The given root packages; and
+    /// 2. The dependencies of those packages
+    ///
+    /// The dependencies of transparent packages are not followed. Transparent packages that are
+    /// reachable remain in the graph, but with their dependencies removed.
+    ///
+    /// Excluded packages (other than the roots themselves) are removed from the graph, along with
+    /// any of their dependencies that are not reachable by some other path. The dependency edges
+    /// that were removed as a result are returned.
+    pub fn prune(
+        &mut self,
+        roots: &[PackageId],
+        excluded: &HashSet<PackageId>,
+    ) -> Vec<ExcludedDependency> {
+        let filtered_packages = self.reachable(roots, excluded);
+
+        let mut excluded_dependencies = Vec::new();
+        for (id, node) in self.inner.iter() {
+            if !filtered_packages.contains(id) || node.is_transparent {
+                continue;
+            }
 
+            for dep in node.dependencies.iter() {
+                if !filtered_packages.contains(&dep.id)
+                    && let Some(dependency) = self.inner.get(&dep.id)
+                {
+                    excluded_dependencies.push(ExcludedDependency {
+                        dependent: node.name.clone(),
+                        dependency: dependency.name.clone(),
+                    });
+                }
+            }
+        }
+
+        self.inner.retain(|id, _| filtered_packages.contains(id));
+
+        for node in self.inner.values_mut() {
+            if node.is_transparent {
+                node.dependencies.clear();
+            } else {
+                node.dependencies
+                    .retain(|dep| filtered_packages.contains(&dep.id));
+            }
+        }
+
+        excluded_dependencies
+    }
+
+    /// Returns the IDs of the packages that would remain in the graph if it were pruned with the
+    /// given roots and excluded packages. See [`CrateGraph::prune`].
+    pub fn reachable(
+        &self,
+        roots: &[PackageId],
+        excluded: &HashSet<PackageId>,
+    ) -> HashSet<PackageId> {
         let mut filtered_packages: HashSet<PackageId> = HashSet::default();
-        let mut stack = vec![id];
+        let mut stack: Vec<&PackageId> = roots.iter().collect();
 
         while let Some(id) = stack.pop() {
             let Some(pkg) = self.inner.get(id) else {
                 continue;
             };
 
-            for descendant in pkg.dependencies.iter() {
-                if !filtered_packages.contains(&descendant.id) {
-                    stack.push(&descendant.id);
+            if !pkg.is_transparent {
+                for descendant in pkg.dependencies.iter() {
+                    if !filtered_packages.contains(&descendant.id)
+                        && !excluded.contains(&descendant.id)
+                    {
+                        stack.push(&descendant.id);
+                    }
                 }
             }
 
             filtered_packages.insert(id.clone());
         }
 
-        self.inner.retain(|id, _| filtered_packages.contains(id));
+        filtered_packages
+    }
 
-        Ok(())
+    /// Returns the percentage of workspace members that are in the given set of packages
+    pub fn workspace_coverage(&self, packages: &HashSet<PackageId>) -> f64 {
+        let members: Vec<&PackageId> = self
+            .inner
+            .iter()
+            .filter(|(_, node)| node.is_workspace_member)
+            .map(|(id, _)| id)
+            .collect();
+
+        if members.is_empty() {
+            return 0.0;
+        }
+
+        let covered = members.iter().filter(|id| packages.contains(id)).count();
+
+        covered as f64 * 100.0 / members.len() as f64
+    }
+
+    /// Marks the workspace members that are more than `max_depth` dependency edges away from all
+    /// of the given root packages as distant. Distant members are emitted as non-members, since
+    /// rust-analyzer assumes that non-member crates don't change, which allows it to avoid watching
+    /// and re-indexing them.
+    pub fn limit_member_depth(&mut self, roots: &[PackageId], max_depth: usize) {
+        let mut distances: HashMap<PackageId, usize> =
+            roots.iter().map(|id| (id.clone(), 0)).collect();
+        let mut queue: VecDeque<PackageId> = roots.iter().cloned().collect();
+
+        while let Some(id) = queue.pop_front() {
+            let Some(pkg) = self.inner.get(&id) else {
+                continue;
+            };
+
+            let distance = distances[&id];
+            if distance == max_depth {
+                continue;
+            }
+
+            for dep in pkg.dependencies.iter() {
+                if !distances.contains_key(&dep.id) {
+                    distances.insert(dep.id.clone(), distance + 1);
+                    queue.push_back(dep.id.clone());
+                }
+            }
+        }
+
+        for (id, node) in self.inner.iter_mut() {
+            if node.is_workspace_member && !distances.contains_key(id) {
+                debug!(
+                    package = node.name,
+                    "treating distant workspace member as non-member"
+                );
+                node.is_distant_member = true;
+            }
+        }
+    }
+
+    /// Returns the IDs of the packages that have a proc macro or a build script and that are built
+    /// for the target when the given packages and their tests are built. These are the packages
+    /// that need to be built for rust-analyzer.
+    ///
+    /// Packages that are only built for the host (i.e. dependencies of proc macros and build
+    /// scripts) are left out. Their proc macros and build scripts are still built when the packages
+    /// that depend on them are built.
+    pub fn compile_time_packages(&self, roots: &[PackageId]) -> Vec<PackageId> {
+        let mut packages: Vec<PackageId> = self
+            .built_packages(roots, true)
+            .into_iter()
+            .filter(|id| {
+                let node = &self.inner[*id];
+                node.is_proc_macro() || node.has_build_script()
+            })
+            .cloned()
+            .collect();
+        packages.sort();
+
+        packages
+    }
+
+    /// Returns the number of proc macros and build scripts that cargo builds when the given
+    /// packages and their tests are built, including the ones that are only built for the host.
+    /// Units that are built for both the host and the target are only counted once.
+    pub fn compile_time_units(&self, roots: &[PackageId]) -> usize {
+        self.built_packages(roots, false)
+            .into_iter()
+            .map(|id| {
+                let node = &self.inner[id];
+                usize::from(node.is_proc_macro()) + usize::from(node.has_build_script())
+            })
+            .sum()
+    }
+
+    /// Returns the IDs of the packages that cargo builds when the given packages and their tests
+    /// are built. Like in cargo, only the dev-dependencies of the given packages themselves are
+    /// built. If `target_only` is set, the packages that are only built for the host are left out.
+    fn built_packages<'a>(
+        &'a self,
+        roots: &'a [PackageId],
+        target_only: bool,
+    ) -> HashSet<&'a PackageId> {
+        let mut visited: HashSet<&PackageId> = HashSet::new();
+        let mut stack: Vec<&PackageId> = roots.iter().collect();
+        while let Some(id) = stack.pop() {
+            let Some(node) = self.inner.get(id) else {
+                continue;
+            };
+
+            if !visited.insert(id) || (target_only && node.is_proc_macro()) {
+                continue;
+            }
+
+            let is_root = roots.contains(id);
+            stack.extend(
+                node.dependencies
+                    .iter()
+                    .filter(|dep| is_root || !dep.is_dev_only)
+                    .filter(|dep| !target_only || !dep.is_build_only)
+                    .map(|dep| &dep.id),
+            );
+        }
+
+        visited
+    }
+
+    /// Returns the IDs of the workspace members matched by the given selector. Third party
+    /// packages are never selected, even if their names match.
+    pub fn select(&self, selector: &PackageSelector) -> Result<Vec<PackageId>> {
+        let matcher = selector.matcher()?;
+
+        Ok(self
+            .inner
+            .iter()
+            .filter(|(_, node)| node.is_workspace_member)
+            .filter(|(_, node)| {
+                let dir = node.manifest_path.parent().unwrap();
+                let relative_dir = dir.strip_prefix(&self.workspace_root).ok();
+
+                matcher.matches(&node.name, relative_dir)
+            })
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+
+    /// Returns the ID of the package with the given manifest path
+    pub fn find_package(&self, manifest_path: FilePath<'_>) -> Result<&PackageId> {
+        // Compare canonical paths, since the manifest path may point to the workspace through a
+        // symlink
+        let canonical = manifest_path.canonicalize()?;
+        let found = self
+            .inner
+            .iter()
+            .find(|(_, node)| node.manifest_path == canonical)
+            .or_else(|| {
+                self.inner.iter().find(|(_, node)| {
+                    node.manifest_path
+                        .as_file_path()
+                        .canonicalize()
+                        .is_ok_and(|path| path == canonical)
+                })
+            });
+
+        let Some((id, _)) = found else {
+            anyhow::bail!(
+                "Could not find workspace member with manifest path {}",
+                manifest_path.as_ref().display()
+            )
+        };
+
+        Ok(id)
     }
 
     pub fn into_crates(self) -> Result<Vec<Crate>> {
         let mut crates = Vec::new();
         let mut deps = Vec::new();
         let mut indexes: HashMap<PackageId, usize> = HashMap::new();
+        let cfgs = self.cargo_config.cfgs();
 
         for (id, package) in self.inner.into_iter() {
             // Represents the indices of the `crates` array corresponding to lib targets for this
@@ -146,14 +403,14 @@ impl CrateGraph {
                 })
                 .collect();
 
-            let mut env = HashMap::new();
+            let mut env = self.cargo_config.env.clone();
             let mut include_dirs = vec![package.manifest_path.parent().unwrap().to_string()];
             if let Some(script) = package.build_script {
                 env.insert("OUT_DIR".into(), script.out_dir.to_string());
 
                 if let Some(parent) = script.out_dir.parent() {
                     include_dirs.push(parent.to_string());
-                    env.extend(script.env.clone().into_iter());
+                    env.extend(script.env.clone());
                 }
             }
 
@@ -181,7 +438,7 @@ impl CrateGraph {
                     edition: target.edition,
                     version: Some(package.version.to_string()),
                     deps: this_deps,
-                    is_workspace_member: package.is_workspace_member,
+                    is_workspace_member: package.is_workspace_member && !package.is_distant_member,
                     is_proc_macro: target.is_proc_macro(),
                     repository: package.repository.clone(),
                     build: Some(BuildInfo {
@@ -200,6 +457,7 @@ impl CrateGraph {
                         .clone()
                         .into_iter()
                         .map(|feature| format!("feature=\"{feature}\""))
+                        .chain(cfgs.iter().cloned())
                         .collect(),
                     target: None,
                     env: env.clone(),
@@ -212,7 +470,7 @@ impl CrateGraph {
             }
         }
 
-        for (c, deps) in crates.iter_mut().zip(deps.into_iter()) {
+        for (c, deps) in crates.iter_mut().zip(deps) {
             c.deps.extend(deps.into_iter().map(|dep| Dep {
                 name: dep.name,
                 crate_index: indexes.get(&dep.id).copied().unwrap(),
@@ -227,13 +485,21 @@ impl CrateGraph {
 }
 
 /// Represents one target of a single package
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PackageNode {
     pub name: String,
     pub targets: Vec<Target>,
     pub manifest_path: FilePathBuf,
     pub version: Version,
     pub is_workspace_member: bool,
+    /// Whether this workspace member is too far from the crates being discovered to be treated as
+    /// a member by rust-analyzer
+    #[serde(default)]
+    pub is_distant_member: bool,
+    /// Whether this package is a `workspace-hack` crate managed by cargo-hakari
+    pub is_workspace_hack: bool,
+    /// Whether the dependencies of this package should be ignored when pruning the graph
+    pub is_transparent: bool,
     pub repository: Option<String>,
     pub features: Vec<String>,
     pub dependencies: Vec<Dependency>,
@@ -241,13 +507,45 @@ pub struct PackageNode {
     pub proc_macro_dylib: Option<FilePathBuf>,
 }
 
-#[derive(Clone)]
+impl PackageNode {
+    /// Returns true if this package has a proc macro target
+    pub fn is_proc_macro(&self) -> bool {
+        self.targets.iter().any(|target| target.is_proc_macro())
+    }
+
+    /// Returns true if this package has a build script
+    pub fn has_build_script(&self) -> bool {
+        self.targets.iter().any(|target| {
+            target
+                .kind
+                .iter()
+                .any(|k| matches!(k, cargo_metadata::TargetKind::CustomBuild))
+        })
+    }
+}
+
+/// A dependency edge that was removed from the graph because the dependency was excluded
+#[derive(Clone, Debug)]
+pub struct ExcludedDependency {
+    pub dependent: String,
+    pub dependency: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Dependency {
     pub id: PackageId,
     pub name: String,
+    /// Whether this is only a dev-dependency, i.e. it's only built for tests, examples and
+    /// benchmarks
+    #[serde(default)]
+    pub is_dev_only: bool,
+    /// Whether this is only a build dependency, i.e. it's only built for the host, for the build
+    /// script
+    #[serde(default)]
+    pub is_build_only: bool,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Target {
     pub name: String,
     pub edition: Edition,
@@ -262,3 +560,162 @@ impl Target {
             .any(|k| matches!(k, cargo_metadata::TargetKind::ProcMacro))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(name: &str) -> PackageId {
+        PackageId {
+            repr: format!("{name}#0.1.0"),
+        }
+    }
+
+    fn dependency(name: &str, kind: DependencyKind) -> Dependency {
+        Dependency {
+            id: id(name),
+            name: name.to_string(),
+            is_dev_only: kind == DependencyKind::Development,
+            is_build_only: kind == DependencyKind::Build,
+        }
+    }
+
+    /// A package's name, whether it's a workspace member, the kinds of its targets, and its
+    /// dependencies
+    type TestPackage<'a> = (
+        &'a str,
+        bool,
+        &'a [cargo_metadata::TargetKind],
+        &'a [(&'a str, DependencyKind)],
+    );
+
+    /// Builds a graph of the given packages, all with version 0.1.0
+    fn graph(packages: &[TestPackage<'_>]) -> CrateGraph {
+        // The paths only need to exist
+        let path: FilePathBuf = Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("Cargo.toml")
+            .try_into()
+            .unwrap();
+
+        let inner = packages
+            .iter()
+            .map(|(name, is_workspace_member, kinds, dependencies)| {
+                let targets = kinds
+                    .iter()
+                    .map(|kind| Target {
+                        name: name.to_string(),
+                        edition: Edition::E2021,
+                        kind: vec![kind.clone()],
+                        root_module: path.clone(),
+                    })
+                    .collect();
+                let node = PackageNode {
+                    name: name.to_string(),
+                    targets,
+                    manifest_path: path.clone(),
+                    version: Version::new(0, 1, 0),
+                    is_workspace_member: *is_workspace_member,
+                    is_distant_member: false,
+                    is_workspace_hack: false,
+                    is_transparent: false,
+                    repository: None,
+                    features: Vec::new(),
+                    dependencies: dependencies
+                        .iter()
+                        .map(|(name, kind)| dependency(name, *kind))
+                        .collect(),
+                    build_script: None,
+                    proc_macro_dylib: None,
+                };
+
+                (id(name), node)
+            })
+            .collect();
+
+        CrateGraph {
+            inner,
+            workspace_root: Utf8PathBuf::from("/workspace"),
+            target_directory: Utf8PathBuf::from("/workspace/target"),
+            cargo_config: CargoConfig::default(),
+        }
+    }
+
+    /// A workspace whose `app` depends on proc macros and build scripts in every possible way
+    fn workspace() -> CrateGraph {
+        use DependencyKind::{Build, Development, Normal};
+        use cargo_metadata::TargetKind::{CustomBuild, Lib, ProcMacro};
+
+        graph(&[
+            (
+                "app",
+                true,
+                &[Lib, CustomBuild],
+                &[
+                    ("lib", Normal),
+                    ("serde", Normal),
+                    ("macros", Normal),
+                    ("test-macros", Development),
+                    ("cc", Build),
+                ],
+            ),
+            ("lib", true, &[Lib], &[("lib-test-macros", Development)]),
+            ("lib-test-macros", true, &[ProcMacro], &[]),
+            ("macros", true, &[ProcMacro], &[]),
+            ("test-macros", true, &[ProcMacro], &[]),
+            (
+                "serde",
+                false,
+                &[Lib, CustomBuild],
+                &[("serde_derive", Normal)],
+            ),
+            (
+                "serde_derive",
+                false,
+                &[ProcMacro],
+                &[("proc-macro2", Normal)],
+            ),
+            ("proc-macro2", false, &[Lib, CustomBuild], &[]),
+            ("cc", false, &[Lib, CustomBuild], &[]),
+        ])
+    }
+
+    #[test]
+    fn compile_time_units_include_host_only_units_and_root_dev_dependencies() {
+        let graph = workspace();
+
+        // The build scripts of `app`, `serde`, `proc-macro2` and `cc`, and the proc macros
+        // `serde_derive`, `macros` and `test-macros`. The dev-dependencies of `lib` aren't built
+        assert_eq!(graph.compile_time_units(&[id("app")]), 7);
+        assert_eq!(graph.compile_time_units(&[id("lib")]), 1);
+        assert_eq!(graph.compile_time_units(&[id("app"), id("lib")]), 8);
+    }
+
+    #[test]
+    fn compile_time_units_count_shared_dependencies_once() {
+        use DependencyKind::{Build, Normal};
+        use cargo_metadata::TargetKind::{CustomBuild, Lib};
+
+        // `shared` is built for both the target and the host
+        let graph = graph(&[
+            (
+                "app",
+                true,
+                &[Lib, CustomBuild],
+                &[("shared", Normal), ("shared", Build)],
+            ),
+            ("shared", false, &[Lib, CustomBuild], &[]),
+        ]);
+
+        assert_eq!(graph.compile_time_units(&[id("app")]), 2);
+    }
+
+    #[test]
+    fn compile_time_packages_leave_out_host_only_packages() {
+        let graph = workspace();
+
+        assert_eq!(
+            graph.compile_time_packages(&[id("app")]),
+            ["app", "macros", "serde", "serde_derive", "test-macros"].map(id)
+        );
+    }
+}
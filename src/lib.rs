@@ -1,24 +1,45 @@
+mod cargo_config;
+pub mod children;
 pub mod cli;
+pub mod config;
 mod discover;
 mod graph;
 mod rust_project;
+pub mod session;
 pub mod util;
 
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader};
 use std::process::Stdio;
 
 use anyhow::{Result, anyhow};
 use cargo_metadata::camino::Utf8PathBuf;
 use tracing::debug;
 
-use crate::cli::CheckArgs;
-use crate::util::{FilePathBuf, Toolchain};
+use crate::cli::{CheckArgs, NetworkArgs, ResourceArgs};
+use crate::config::Config;
+use crate::util::{FilePathBuf, PathMapping, Toolchain};
 
 pub use discover::DiscoverRunner;
+pub use graph::CrateGraph;
 pub use rust_project::ProjectJson;
 
-pub fn check(command: &'static str, args: CheckArgs, cargo_home: Option<PathBuf>) -> Result<()> {
-    let manifest = find_manifest(args.path.into())?;
+pub fn check(
+    command: &'static str,
+    args: CheckArgs,
+    toolchain: Toolchain,
+    network: NetworkArgs,
+    resources: ResourceArgs,
+    config: &Config,
+) -> Result<()> {
+    let toolchain = toolchain
+        .with_network(network.with_config(config))
+        .with_resources(resources.with_config(config));
+    let path = util::remap(&args.path, &config.input_mappings());
+    // Cargo reports canonical paths, so diagnostics are mapped back through any symlinks in the
+    // path the editor uses before the configured mappings are applied
+    let symlink_mappings = PathMapping::symlinks(&path);
+    let output_mappings = config.output_mappings();
+    let manifest = find_manifest(path)?;
     let message_format = if util::is_tty() {
         "--message-format=human"
     } else if args.disable_color_diagnostics {
@@ -27,7 +48,17 @@ pub fn check(command: &'static str, args: CheckArgs, cargo_home: Option<PathBuf>
         "--message-format=json-diagnostic-rendered-ansi"
     };
 
-    let mut cmd = Toolchain::new(cargo_home.clone()).cargo();
+    let toolchain = match manifest.parent() {
+        Some(dir) => toolchain.for_workspace(dir)?,
+        None => toolchain,
+    };
+    let mut cmd = toolchain.cargo_build();
+
+    // Run cargo from the same directory as discover does, so that it uses the same
+    // `.cargo/config.toml` files
+    if let Some(dir) = manifest.parent() {
+        cmd.current_dir(dir);
+    }
 
     cmd.arg(command)
         .arg(message_format)
@@ -35,14 +66,49 @@ pub fn check(command: &'static str, args: CheckArgs, cargo_home: Option<PathBuf>
         .arg("--all-targets")
         .arg("--manifest-path")
         .arg(manifest.as_file_path())
-        .stdout(Stdio::inherit())
+        .args(toolchain.network_args())
+        .args(toolchain.build_args())
         .stderr(Stdio::inherit());
 
+    // The paths in diagnostics need to be mapped to the paths the editor sees, so we can only let
+    // cargo write directly to stdout if there are no mappings
+    if symlink_mappings.is_empty() && output_mappings.is_empty() {
+        cmd.stdout(Stdio::inherit());
+    } else {
+        cmd.stdout(Stdio::piped());
+    }
+
+    // Share discover's target directory, so that its build artifacts are reused
+    if let Some(target_dir) = config.target_dir.as_ref() {
+        cmd.arg("--target-dir").arg(target_dir);
+    }
+
+    // Build with the same features as discover, so that the diagnostics match the cfgs that
+    // rust-analyzer sees
+    let features = args.features.with_config(config)?;
+    cmd.args(features.cargo_args(&[&manifest]));
+
     for arg in args.passthrough_args {
         cmd.arg(arg);
     }
 
-    let status = cmd.spawn()?.wait()?;
+    let mut child = children::spawn(&mut cmd)?;
+
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(mut message) => {
+                    util::remap_json(&mut message, &symlink_mappings);
+                    util::remap_json(&mut message, &output_mappings);
+                    println!("{message}");
+                }
+                Err(_) => println!("{line}"),
+            }
+        }
+    }
+
+    let status = child.wait()?;
 
     if status.success() {
         Ok(())
@@ -51,17 +117,20 @@ pub fn check(command: &'static str, args: CheckArgs, cargo_home: Option<PathBuf>
     }
 }
 
+/// Returns the manifest of the package containing the given path. The manifest's path is
+/// canonical, since that's the form of the paths that cargo reports.
 pub fn find_manifest(path: Utf8PathBuf) -> Result<FilePathBuf> {
     let path = std::path::absolute(&path)?;
     let Some(parent) = path.parent() else {
         anyhow::bail!("Invalid path: could not get parent");
     };
+    let parent = parent.canonicalize()?;
 
     for ancestor in parent.ancestors() {
         for item in std::fs::read_dir(ancestor)? {
             let item = item?;
             if item.file_type()?.is_file() && item.file_name() == "Cargo.toml" {
-                let path = std::path::absolute(item.path())?;
+                let path = item.path();
                 debug!(manifest_path = %path.display());
 
                 return path.try_into();
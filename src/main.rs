@@ -3,21 +3,27 @@ use std::{
     fs::{self, File},
     io::{self, IsTerminal},
     path::PathBuf,
+    process::{Command, Stdio},
     time::Instant,
 };
 
-use anyhow::{Result, anyhow};
-use cargo_metadata::camino::Utf8PathBuf;
-use cargo_subspace::{DiscoverRunner, ProjectJson, check, find_manifest};
+use anyhow::Result;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use cargo_subspace::children;
+use cargo_subspace::config::Config;
+use cargo_subspace::session::Session;
+use cargo_subspace::{CrateGraph, DiscoverRunner, ProjectJson, check, find_manifest};
 use cargo_subspace::{
-    cli::{CargoSubspace, DiscoverArgument, DiscoverProjectData, SubspaceCommand},
-    util::{self, Toolchain},
+    cli::{
+        CargoSubspace, DiscoverArgument, DiscoverProjectData, NetworkArgs, ResourceArgs,
+        SubspaceCommand,
+    },
+    util::{self, FilePathBuf, PathMapping, Toolchain},
 };
 use clap::Parser;
-use tracing::{debug, error, level_filters::LevelFilter};
+use tracing::{debug, error, level_filters::LevelFilter, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 
-const DEFAULT_LOG_LOCATION: &str = ".local/state/cargo-subspace";
 const LOG_FILE_NAME: &str = "cargo-subspace.log";
 
 fn main() -> Result<()> {
@@ -31,7 +37,21 @@ fn main() -> Result<()> {
     let dir = env::current_dir()?;
     debug!(path, cwd = %dir.display(), %version, ?command, ?args);
 
-    run_inner(args.command, args.cargo_home).inspect_err(|e| {
+    // rust-analyzer cancels discover and check by killing us, which must also stop any builds we
+    // started, since they'd otherwise keep holding cargo's build lock
+    children::terminate_on_cancel()?;
+
+    let toolchain = Toolchain::new(args.cargo_home, args.toolchain);
+
+    run_inner(
+        args.command,
+        toolchain,
+        args.network,
+        args.resources,
+        args.target_dir,
+        args.config,
+    )
+    .inspect_err(|e| {
         error!("{e}");
 
         let error = DiscoverProjectData::Error {
@@ -43,16 +63,43 @@ fn main() -> Result<()> {
     })
 }
 
-fn run_inner(command: SubspaceCommand, cargo_home: Option<PathBuf>) -> Result<()> {
+fn run_inner(
+    command: SubspaceCommand,
+    toolchain: Toolchain,
+    network: NetworkArgs,
+    resources: ResourceArgs,
+    target_dir: Option<Utf8PathBuf>,
+    config_path: Option<Utf8PathBuf>,
+) -> Result<()> {
     let execution_start = Instant::now();
+    let target_dir: Option<Utf8PathBuf> = match target_dir {
+        Some(dir) => Some(std::path::absolute(dir)?.try_into()?),
+        None => None,
+    };
+
+    // The target directory given on the command line overrides the one in the config
+    let load_config = |start: &Utf8Path| -> Result<Config> {
+        let mut config = Config::load(config_path.as_deref(), start)?;
+        if target_dir.is_some() {
+            config.target_dir = target_dir.clone();
+        }
+
+        Ok(config)
+    };
 
     match command {
         SubspaceCommand::Version => {
             println!("{}", version());
         }
         SubspaceCommand::Discover {
-            all_features,
-            no_default_features,
+            features,
+            timeouts,
+            member_depth,
+            group,
+            full,
+            sysroot_src,
+            background_build,
+            warm,
             #[cfg(not(target_os = "windows"))]
             mut flamegraph,
             arg,
@@ -73,60 +120,159 @@ fn run_inner(command: SubspaceCommand, cargo_home: Option<PathBuf>) -> Result<()
                     .transpose()?
             };
 
-            let toolchain = Toolchain::new(cargo_home);
-            let manifest_path = match arg {
-                DiscoverArgument::Path(path) => find_manifest(path)?,
-                DiscoverArgument::Buildfile(manifest_path) => manifest_path,
+            let mut config = load_config(arg.path())?;
+            let timeouts = timeouts.with_config(&config);
+            let toolchain = toolchain
+                .with_network(network.with_config(&config))
+                .with_resources(resources.with_config(&config))
+                .with_sysroot_timeout(timeouts.sysroot());
+
+            // The editor may see the workspace at a different path than cargo does (e.g. if cargo
+            // runs in a container), so the argument is mapped to the path that cargo sees
+            let input_mappings = config.input_mappings();
+
+            // Cargo reports canonical paths, but the editor may have opened the workspace through a
+            // symlink. Discover the canonical manifest, and then map the paths in the project back
+            // to the form the editor uses so that rust-analyzer can match them to open files
+            let path = Utf8PathBuf::try_from(std::path::absolute(arg.path())?)?;
+            let path = util::remap(&path, &input_mappings);
+            let symlink_mappings = PathMapping::symlinks(&path);
+            let sysroot_src = sysroot_src.or(config.sysroot_src.clone());
+            let manifest_path: FilePathBuf = match arg {
+                DiscoverArgument::Path(_) => {
+                    let cargo_home = toolchain.cargo_home();
+                    let sysroot = toolchain.sysroot()?;
+
+                    // Don't discover dependencies of the workspace. Instead, re-emit the project of
+                    // a workspace that depends on them
+                    if util::is_dependency_source(
+                        &path,
+                        cargo_home.as_deref(),
+                        &sysroot,
+                        sysroot_src.as_deref(),
+                    ) {
+                        debug!(%path, "path is not part of a workspace");
+                        let Some(output) = Session::find_project_for_dependency(&path)? else {
+                            anyhow::bail!(
+                                "`{path}` belongs to a dependency, and no discovered workspace \
+                                 depends on it"
+                            );
+                        };
+
+                        println!("{output}");
+
+                        return Ok(());
+                    }
+
+                    find_manifest(path)?
+                }
+                DiscoverArgument::Buildfile(_) => path.canonicalize_utf8()?.try_into()?,
             };
 
-            let mut runner = DiscoverRunner::new(toolchain.clone(), manifest_path.clone());
-            runner = match (all_features, no_default_features) {
-                (false, false) => runner.with_default_features(),
-                (true, false) => runner.with_all_features(),
-                (false, true) => runner.with_no_default_features(),
-                (true, true) => unreachable!("disallowed by clap"),
+            // The workspace may pin its toolchain (e.g. with `rust-toolchain.toml`), so the exact
+            // binaries can only be resolved once we know where the workspace is
+            let toolchain = match manifest_path.parent() {
+                Some(dir) => toolchain.for_workspace(dir)?,
+                None => toolchain,
             };
+            let sysroot = toolchain.sysroot()?;
+
+            let output_mappings = config.output_mappings();
+            debug!(?symlink_mappings, ?input_mappings, ?output_mappings);
+
+            if member_depth.is_some() {
+                config.member_depth = member_depth;
+            }
+            config.full |= full;
+            // The background process that builds for the next discovery always builds right away
+            let background_build = (background_build || config.background_build) && !warm;
+            let features = features.with_config(&config)?;
+
+            let sysroot_src =
+                sysroot_src.unwrap_or_else(|| sysroot.join("lib/rustlib/src/rust/library"));
+
+            // Without the standard library's source code, rust-analyzer can't resolve anything
+            // from std, so make sure the user finds out why
+            let sysroot_src = if sysroot_src.is_dir() {
+                Some(sysroot_src)
+            } else {
+                warn!(%sysroot_src, "sysroot source directory does not exist");
+                util::log_progress(format!(
+                    "Warning: the standard library source code was not found at `{sysroot_src}`. \
+                     Install it with `rustup component add rust-src`, or set `sysroot-src`"
+                ))?;
 
-            let crates = runner.run()?.into_crates()?;
-
-            let p: PathBuf = String::from_utf8(
-                toolchain
-                    .rustc()
-                    .arg("--print")
-                    .arg("sysroot")
-                    .output()?
-                    .stdout,
-            )?
-            .trim()
-            .into();
-
-            let sysroot = Utf8PathBuf::from_path_buf(p)
-                .map_err(|_| anyhow!("Path contains non-UTF-8 characters"))?;
-            let sysroot_src = sysroot.join("lib/rustlib/src/rust/library");
-
-            let project = ProjectJson {
-                sysroot,
-                sysroot_src: Some(sysroot_src),
-                // TODO: do i need this? buck excludes it...
-                // sysroot_project: None,
-                // TODO: do i need this? buck excludes it...
-                // cfg_groups: HashMap::new(),
-                crates,
-                // TODO: Add support for runnables
-                runnables: vec![],
+                None
             };
 
-            let output = DiscoverProjectData::Finished {
-                buildfile: manifest_path.to_path_buf(),
-                project,
+            let emit = |graph: CrateGraph| -> Result<()> {
+                util::log_progress("Serializing project")?;
+
+                let target_directory = graph.target_directory.clone();
+                let mut project = ProjectJson {
+                    sysroot: sysroot.clone(),
+                    sysroot_src: sysroot_src.clone(),
+                    // TODO: do i need this? buck excludes it...
+                    // sysroot_project: None,
+                    // TODO: do i need this? buck excludes it...
+                    // cfg_groups: HashMap::new(),
+                    crates: graph.into_crates()?,
+                    // TODO: Add support for runnables
+                    runnables: vec![],
+                };
+
+                project.remap(&symlink_mappings);
+                project.remap(&output_mappings);
+
+                let output = DiscoverProjectData::Finished {
+                    buildfile: manifest_path
+                        .remap(&symlink_mappings)
+                        .remap(&output_mappings)
+                        .into(),
+                    project,
+                };
+                let json = if util::is_tty() {
+                    serde_json::to_string_pretty(&output)?
+                } else {
+                    serde_json::to_string(&output)?
+                };
+
+                println!("{json}");
+
+                if let Err(e) =
+                    Session::save_project(&target_directory, sysroot_src.as_deref(), &json)
+                {
+                    warn!("Failed to save project: {e:#}");
+                }
+
+                Ok(())
             };
-            let json = if util::is_tty() {
-                serde_json::to_string_pretty(&output)?
+
+            let runner = DiscoverRunner::new(toolchain.clone(), manifest_path.clone())
+                .with_config(config)
+                .with_group(group)
+                .with_features(features)
+                .with_timeouts(timeouts)
+                .with_build(!background_build);
+
+            // Only one background build runs at a time, since they'd all build the same thing
+            let _background_build_lock = if warm {
+                match runner.lock_background_build()? {
+                    Some(lock) => Some(lock),
+                    None => {
+                        debug!("another background build is already running");
+                        return Ok(());
+                    }
+                }
             } else {
-                serde_json::to_string(&output)?
+                None
             };
 
-            println!("{json}");
+            emit(runner.run()?)?;
+
+            if background_build {
+                spawn_background_build()?;
+            }
 
             #[cfg(not(target_os = "windows"))]
             if let Some((guard, path)) = pprof_guard {
@@ -136,8 +282,14 @@ fn run_inner(command: SubspaceCommand, cargo_home: Option<PathBuf>) -> Result<()
                 report.flamegraph(file)?;
             }
         }
-        SubspaceCommand::Check { args } => check("check", args, cargo_home)?,
-        SubspaceCommand::Clippy { args } => check("clippy", args, cargo_home)?,
+        SubspaceCommand::Check { args } => {
+            let config = load_config(&args.path)?;
+            check("check", args, toolchain, network, resources, &config)?
+        }
+        SubspaceCommand::Clippy { args } => {
+            let config = load_config(&args.path)?;
+            check("clippy", args, toolchain, network, resources, &config)?
+        }
     }
 
     debug!(execution_time_seconds = execution_start.elapsed().as_secs_f32());
@@ -145,6 +297,29 @@ fn run_inner(command: SubspaceCommand, cargo_home: Option<PathBuf>) -> Result<()
     Ok(())
 }
 
+/// Runs this discovery again in a detached process, which builds the proc macros and build scripts
+/// so that the next discovery can use them. The process keeps running if we're cancelled.
+///
+/// The project can't be emitted again once the build has finished instead: rust-analyzer treats the
+/// first `Finished` message as the end of the discovery and drops its handle on our process, which
+/// kills our process group. That's also why the background build runs in its own process group.
+fn spawn_background_build() -> Result<()> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(env::args_os().skip(1))
+        .env("CARGO_SUBSPACE_WARM", "true")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    #[cfg(not(target_os = "windows"))]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    let child = cmd.spawn()?;
+    debug!(pid = child.id(), "started background build");
+
+    Ok(())
+}
+
 fn version() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
@@ -161,13 +336,10 @@ fn set_up_tracing(log_location: Option<PathBuf>, verbose: bool) -> Result<Option
 
         Ok(None)
     } else {
-        #[cfg(not(target_os = "windows"))]
-        let home: PathBuf = env::var("HOME")?.into();
-        #[cfg(target_os = "windows")]
-        let home: PathBuf = env::var("USERPROFILE")?.into();
-        let log_location = log_location
-            .clone()
-            .unwrap_or_else(|| home.join(DEFAULT_LOG_LOCATION));
+        let log_location = match log_location {
+            Some(log_location) => log_location,
+            None => util::state_dir()?.into(),
+        };
 
         let level = if verbose {
             LevelFilter::DEBUG
@@ -2,10 +2,10 @@ use std::collections::HashMap;
 use std::fmt::Display;
 
 use cargo_metadata::Edition;
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
 use serde::Serialize;
 
-use crate::util::FilePathBuf;
+use crate::util::{self, FilePathBuf, PathMapping};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ProjectJson {
@@ -86,6 +86,25 @@ pub struct ProjectJson {
     pub runnables: Vec<Runnable>,
 }
 
+impl ProjectJson {
+    /// Applies the given path mappings to every path in the project
+    pub fn remap(&mut self, mappings: &[PathMapping]) {
+        if mappings.is_empty() {
+            return;
+        }
+
+        self.sysroot = util::remap(&self.sysroot, mappings);
+        self.sysroot_src = self
+            .sysroot_src
+            .as_deref()
+            .map(|src| util::remap(src, mappings));
+
+        for krate in self.crates.iter_mut() {
+            krate.remap(mappings);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Crate {
     /// Optional crate name used for display purposes,
@@ -171,6 +190,41 @@ pub struct Crate {
     pub proc_macro_cwd: Option<FilePathBuf>,
 }
 
+impl Crate {
+    fn remap(&mut self, mappings: &[PathMapping]) {
+        let remap_str = |path: &str| util::remap(Utf8Path::new(path), mappings).into_string();
+
+        self.root_module = self.root_module.remap(mappings);
+        self.proc_macro_dylib_path = self
+            .proc_macro_dylib_path
+            .as_ref()
+            .map(|path| path.remap(mappings));
+        self.proc_macro_cwd = self
+            .proc_macro_cwd
+            .as_ref()
+            .map(|path| path.remap(mappings));
+
+        if let Some(source) = self.source.as_mut() {
+            for dir in source.include_dirs.iter_mut() {
+                *dir = remap_str(dir);
+            }
+        }
+
+        if let Some(build) = self.build.as_mut() {
+            build.build_file = remap_str(&build.build_file);
+        }
+
+        // Environment variables set by build scripts (e.g. `OUT_DIR`) often contain paths, but only
+        // values that are actually paths are remapped
+        for value in self.env.values_mut() {
+            let path = Utf8Path::new(value);
+            if path.is_absolute() && path.exists() {
+                *value = remap_str(value);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Runnable {
     /// The program invoked by the runnable.
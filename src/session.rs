@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, TryLockError};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use cargo_metadata::{
+    BuildScript, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::graph::CrateGraph;
+use crate::util;
+
+const SESSION_DIR: &str = "cargo-subspace";
+const SESSION_FILE_NAME: &str = "session.json";
+const PROJECT_FILE_NAME: &str = "project.json";
+const WORKSPACES_FILE_NAME: &str = "workspaces.json";
+const LOCK_FILE_NAME: &str = "discover.lock";
+const BACKGROUND_BUILD_LOCK_FILE_NAME: &str = "background-build.lock";
+const CACHED_GRAPH_FILE_NAME: &str = "metadata.json";
+
+/// The maximum number of workspaces remembered in the workspace index
+const MAX_WORKSPACES: usize = 32;
+
+/// State that is persisted between invocations of `discover` for a single workspace. It is stored
+/// in the workspace's target directory.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// The packages that owned the manifests discovered by the last successful discovery. Crates
+    /// that were only loaded because of a crate group or `always-load` are not included.
+    pub roots: Vec<PackageId>,
+
+    /// The directories containing the manifests of every package loaded by the last successful
+    /// discovery
+    #[serde(default)]
+    pub package_dirs: Vec<Utf8PathBuf>,
+
+    /// The standard library source code used by the last successful discovery
+    #[serde(default)]
+    pub sysroot_src: Option<Utf8PathBuf>,
+
+    /// The proc macro dylibs built by earlier discoveries
+    #[serde(default)]
+    pub proc_macro_dylibs: BTreeMap<PackageId, Utf8PathBuf>,
+
+    /// The outputs of the build scripts run by earlier discoveries
+    #[serde(default)]
+    pub build_scripts: BTreeMap<PackageId, BuildScript>,
+}
+
+impl Session {
+    /// Loads the session for the workspace with the given target directory. A missing or
+    /// unreadable session is treated as empty.
+    pub fn load(target_directory: &Utf8Path) -> Self {
+        let path = session_dir(target_directory).join(SESSION_FILE_NAME);
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+
+        serde_json::from_str(&contents)
+            .inspect_err(|e| warn!(session_path = %path, "failed to parse session: {e}"))
+            .unwrap_or_default()
+    }
+
+    /// Records the proc macro dylibs and build script outputs in the given graph, alongside the ones
+    /// recorded by earlier discoveries
+    pub fn record_outputs(&mut self, graph: &CrateGraph) {
+        for (id, pkg) in graph.inner.iter() {
+            if let Some(dylib) = pkg.proc_macro_dylib.clone() {
+                self.proc_macro_dylibs.insert(id.clone(), dylib.into());
+            }
+
+            if let Some(script) = pkg.build_script.clone() {
+                self.build_scripts.insert(id.clone(), script);
+            }
+        }
+    }
+
+    /// Forgets the proc macro dylibs and build script outputs of packages that aren't in the given
+    /// (unpruned) graph, and the ones that no longer exist
+    pub fn retain_outputs(&mut self, graph: &CrateGraph) {
+        self.proc_macro_dylibs
+            .retain(|id, dylib| graph.inner.contains_key(id) && dylib.is_file());
+        self.build_scripts
+            .retain(|id, script| graph.inner.contains_key(id) && script.out_dir.is_dir());
+    }
+
+    /// Uses the proc macro dylibs and build script outputs recorded by earlier discoveries for the
+    /// packages in the given graph that haven't been built, as long as they still exist
+    pub fn restore_outputs(&self, graph: &mut CrateGraph) {
+        for (id, pkg) in graph.inner.iter_mut() {
+            if pkg.proc_macro_dylib.is_none()
+                && let Some(dylib) = self.proc_macro_dylibs.get(id)
+                && dylib.is_file()
+            {
+                pkg.proc_macro_dylib = dylib.clone().try_into().ok();
+            }
+
+            if pkg.build_script.is_none()
+                && let Some(script) = self.build_scripts.get(id)
+                && script.out_dir.is_dir()
+            {
+                pkg.build_script = Some(script.clone());
+            }
+        }
+    }
+
+    pub fn save(&self, target_directory: &Utf8Path) -> Result<()> {
+        let path = session_dir(target_directory).join(SESSION_FILE_NAME);
+        debug!(session_path = %path, "saving session");
+
+        write(&path, &serde_json::to_string(self)?)
+    }
+
+    /// Saves the serialized output of the last successful discovery for the workspace with the
+    /// given target directory, and records the workspace in the workspace index so that the
+    /// output can be found again by [`Session::find_project_for_dependency`]. The standard library
+    /// source code it used is recorded too, since the workspace depends on it.
+    pub fn save_project(
+        target_directory: &Utf8Path,
+        sysroot_src: Option<&Utf8Path>,
+        output: &str,
+    ) -> Result<()> {
+        write(
+            &session_dir(target_directory).join(PROJECT_FILE_NAME),
+            output,
+        )?;
+
+        let mut session = Self::load(target_directory);
+        session.sysroot_src = sysroot_src.map(Utf8Path::to_path_buf);
+        session.save(target_directory)?;
+
+        let index_path = workspaces_path()?;
+        let mut workspaces: Vec<Utf8PathBuf> = fs::read_to_string(&index_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+
+        workspaces.retain(|dir| dir != target_directory);
+        workspaces.insert(0, target_directory.to_path_buf());
+        workspaces.truncate(MAX_WORKSPACES);
+
+        write(&index_path, &serde_json::to_string(&workspaces)?)
+    }
+
+    /// Returns the serialized output of the last discovery of a workspace that loaded the package
+    /// containing the given dependency source file (or ran the build script that generated it), if
+    /// there is one
+    pub fn find_project_for_dependency(path: &Utf8Path) -> Result<Option<String>> {
+        let workspaces: Vec<Utf8PathBuf> = match fs::read_to_string(workspaces_path()?) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(_) => return Ok(None),
+        };
+
+        let load_project = |target_directory: &Utf8Path| {
+            fs::read_to_string(session_dir(target_directory).join(PROJECT_FILE_NAME)).ok()
+        };
+
+        let dependent = workspaces.iter().find(|target_directory| {
+            let session = Self::load(target_directory);
+
+            // Build scripts write their output to the target directory, which isn't inside any
+            // package in a virtual workspace
+            session
+                .package_dirs
+                .iter()
+                .chain(session.sysroot_src.as_ref())
+                .chain(session.build_scripts.values().map(|script| &script.out_dir))
+                .any(|dir| path.starts_with(dir))
+        });
+
+        let Some(target_directory) = dependent else {
+            return Ok(None);
+        };
+        debug!(%target_directory, "found workspace that depends on dependency");
+
+        Ok(load_project(target_directory))
+    }
+}
+
+/// An exclusive lock on fetching the metadata of a workspace, which is held until it is dropped.
+/// Concurrent discoveries of the same workspace would otherwise all fetch the same metadata.
+pub struct DiscoverLock {
+    _file: File,
+}
+
+impl DiscoverLock {
+    /// Acquires the lock for the workspace with the given target directory, waiting for any other
+    /// discovery that holds it to finish
+    pub fn acquire(target_directory: &Utf8Path) -> Result<Self> {
+        let path = session_dir(target_directory).join(LOCK_FILE_NAME);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(&path).with_context(|| format!("Failed to create `{path}`"))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                util::log_progress("Waiting for another discovery of this workspace")?;
+                file.lock()
+                    .with_context(|| format!("Failed to lock `{path}`"))?;
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock `{path}`"));
+            }
+        }
+
+        debug!(lock_path = %path, "acquired discover lock");
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// An exclusive lock on building the proc macros and build scripts of a workspace in a background
+/// process, which is held until it is dropped
+pub struct BackgroundBuildLock {
+    _file: File,
+}
+
+impl BackgroundBuildLock {
+    /// Acquires the lock for the workspace with the given target directory, or returns `None` if
+    /// another background build holds it
+    pub fn try_acquire(target_directory: &Utf8Path) -> Result<Option<Self>> {
+        let path = session_dir(target_directory).join(BACKGROUND_BUILD_LOCK_FILE_NAME);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(&path).with_context(|| format!("Failed to create `{path}`"))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock `{path}`"));
+            }
+        }
+
+        debug!(lock_path = %path, "acquired background build lock");
+
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// The crate graph lowered from a workspace's metadata, before it was pruned, which can be reused
+/// by discoveries that were waiting on the discovery that fetched it. Only the graph fetched most
+/// recently is kept.
+#[derive(Serialize, Deserialize)]
+pub struct CachedGraph {
+    /// Identifies the inputs that the metadata was fetched with
+    pub key: String,
+
+    /// When the metadata started being fetched
+    pub started_at: SystemTime,
+
+    /// When the metadata finished being fetched
+    pub finished_at: SystemTime,
+
+    /// The manifests (and lock file) of the workspace that the graph was built from
+    pub manifests: Vec<Utf8PathBuf>,
+
+    pub graph: CrateGraph,
+
+    /// The workspace's explicit default members
+    pub default_members: Vec<PackageId>,
+}
+
+impl CachedGraph {
+    /// Creates a cache entry for metadata that started being fetched at the given time and was
+    /// just lowered into the given graph
+    pub fn new(
+        key: String,
+        started_at: SystemTime,
+        graph: CrateGraph,
+        default_members: Vec<PackageId>,
+    ) -> Self {
+        let manifests = graph
+            .inner
+            .values()
+            .map(|pkg| Utf8PathBuf::from(pkg.manifest_path.clone()))
+            .filter(|path| path.starts_with(&graph.workspace_root))
+            .chain([
+                graph.workspace_root.join("Cargo.toml"),
+                graph.workspace_root.join("Cargo.lock"),
+            ])
+            .collect();
+
+        Self {
+            key,
+            started_at,
+            finished_at: SystemTime::now(),
+            manifests,
+            graph,
+            default_members,
+        }
+    }
+
+    /// Returns the cached graph if it has the given key, it was produced by a discovery that
+    /// finished after the given time (i.e. one that was in progress while we waited for the
+    /// discover lock), and none of the workspace's manifests have changed since that discovery
+    /// started.
+    pub fn load(lock_directory: &Utf8Path, key: &str, waiting_since: SystemTime) -> Option<Self> {
+        let path = session_dir(lock_directory).join(CACHED_GRAPH_FILE_NAME);
+        let contents = fs::read_to_string(&path).ok()?;
+        let cached: Self = serde_json::from_str(&contents)
+            .inspect_err(|e| debug!(graph_path = %path, "failed to parse cached graph: {e}"))
+            .ok()?;
+
+        if cached.key != key || cached.finished_at < waiting_since {
+            return None;
+        }
+
+        let changed = cached.manifests.iter().find(|manifest| {
+            fs::metadata(manifest)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified > cached.started_at)
+        });
+
+        if let Some(manifest) = changed {
+            debug!(%manifest, "manifest changed since cached graph was built");
+
+            return None;
+        }
+
+        Some(cached)
+    }
+
+    pub fn save(&self, lock_directory: &Utf8Path) -> Result<()> {
+        let path = session_dir(lock_directory).join(CACHED_GRAPH_FILE_NAME);
+        debug!(graph_path = %path, "saving graph");
+
+        write(&path, &serde_json::to_string(self)?)
+    }
+}
+
+fn session_dir(target_directory: &Utf8Path) -> Utf8PathBuf {
+    target_directory.join(SESSION_DIR)
+}
+
+fn workspaces_path() -> Result<Utf8PathBuf> {
+    Ok(util::state_dir()?.join(WORKSPACES_FILE_NAME))
+}
+
+/// Writes the given file atomically, since concurrent discoveries may be reading it. The contents
+/// are written to a temporary file in the same directory, which is then renamed into place.
+fn write(path: &Utf8Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = Utf8PathBuf::from(format!("{path}.{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents).with_context(|| format!("Failed to write `{temp_path}`"))?;
+    fs::rename(&temp_path, path)
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+        .with_context(|| format!("Failed to write `{path}`"))
+}
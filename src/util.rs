@@ -1,44 +1,458 @@
 use std::{
-    ffi::OsStr,
+    env,
+    ffi::{OsStr, OsString},
     fmt::Display,
     io::{self, IsTerminal},
     ops::Deref,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Result, anyhow};
-use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{
+    MetadataCommand,
+    camino::{Utf8Path, Utf8PathBuf},
+    cargo_platform::Cfg,
+};
 use serde::{Deserialize, Deserializer, Serialize};
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::cli::DiscoverProjectData;
+use crate::children;
+use crate::cli::{DiscoverProjectData, NetworkArgs, ResourceArgs};
 
 #[derive(Default, Clone)]
 pub struct Toolchain {
     pub cargo_home: Option<PathBuf>,
+    /// The name of a rustup toolchain to use instead of the one pinned by the workspace
+    pub toolchain: Option<String>,
+    cargo: Option<PathBuf>,
+    rustc: Option<PathBuf>,
+    rustc_wrapper: Option<PathBuf>,
+    rustc_workspace_wrapper: Option<PathBuf>,
+    network: NetworkArgs,
+    resources: ResourceArgs,
+    sysroot_timeout: Option<Duration>,
 }
 
 impl Toolchain {
-    pub fn new(cargo_home: Option<PathBuf>) -> Self {
-        Self { cargo_home }
+    /// Creates a toolchain that respects the `CARGO`, `RUSTC`, `RUSTC_WRAPPER` and
+    /// `RUSTC_WORKSPACE_WRAPPER` environment variables, the same way cargo does
+    pub fn new(cargo_home: Option<PathBuf>, toolchain: Option<String>) -> Self {
+        Self {
+            cargo_home,
+            toolchain,
+            cargo: env_path("CARGO"),
+            rustc: env_path("RUSTC"),
+            rustc_wrapper: env_path("RUSTC_WRAPPER"),
+            rustc_workspace_wrapper: env_path("RUSTC_WORKSPACE_WRAPPER"),
+            network: NetworkArgs::default(),
+            resources: ResourceArgs::default(),
+            sysroot_timeout: None,
+        }
     }
 
-    fn cargo_command(&self, cmd: &str) -> Command {
-        if let Some(cargo_home) = self.cargo_home.as_ref() {
-            Command::new(cargo_home.join("bin").join(cmd))
-        } else {
-            Command::new(cmd)
+    /// Passes the given network options to every cargo command
+    pub fn with_network(mut self, network: NetworkArgs) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Returns the network arguments (e.g. `--offline`) that every cargo command must be given
+    pub fn network_args(&self) -> Vec<String> {
+        self.network.cargo_args()
+    }
+
+    /// Applies the given resource limits to every build
+    pub fn with_resources(mut self, resources: ResourceArgs) -> Self {
+        self.resources = resources;
+        self
+    }
+
+    /// Applies the given timeout to resolving the toolchain and querying its sysroot
+    pub fn with_sysroot_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.sysroot_timeout = timeout;
+        self
+    }
+
+    /// Returns the arguments (e.g. `--jobs`) that every cargo build must be given
+    pub fn build_args(&self) -> Vec<String> {
+        self.resources.cargo_args()
+    }
+
+    /// Resolves the exact `cargo` and `rustc` binaries of the toolchain used by the workspace in
+    /// the given directory, as determined by rustup (e.g. from a `rust-toolchain.toml` file), or of
+    /// the explicitly configured toolchain. This ensures that the same toolchain is used for every
+    /// command, even if the rustup proxies aren't on the `PATH`.
+    ///
+    /// Binaries set with the `CARGO` and `RUSTC` environment variables take precedence over the
+    /// workspace's toolchain, but not over an explicitly configured one. If rustup isn't installed
+    /// and no toolchain was explicitly configured, the binaries on the `PATH` (or in the cargo home)
+    /// are used instead.
+    pub fn for_workspace(mut self, dir: &Utf8Path) -> Result<Self> {
+        let rustup = self.bin_path("rustup");
+        let which = |binary: &str, from_env: &Option<PathBuf>| -> Result<Option<PathBuf>> {
+            if self.toolchain.is_none() && from_env.is_some() {
+                return Ok(from_env.clone());
+            }
+
+            let mut cmd = Command::new(&rustup);
+            cmd.arg("which").arg(binary).current_dir(dir);
+
+            if let Some(toolchain) = self.toolchain.as_deref() {
+                cmd.arg("--toolchain").arg(toolchain);
+            }
+
+            let output = match children::output(&mut cmd, self.sysroot_timeout) {
+                Ok(output) => output,
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+                        && self.toolchain.is_none() =>
+                {
+                    debug!("rustup not found; using toolchain binaries from PATH");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.context("Failed to run rustup")),
+            };
+
+            // The toolchain may not be installed yet, in which case the rustup proxies install it
+            // the first time they're run
+            if !output.status.success() {
+                warn!(
+                    stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                    "failed to resolve `{binary}` with rustup; using the rustup proxy instead"
+                );
+                return Ok(None);
+            }
+
+            Ok(Some(String::from_utf8(output.stdout)?.trim().into()))
+        };
+
+        self.cargo = which("cargo", &self.cargo)?;
+        self.rustc = which("rustc", &self.rustc)?;
+        info!(
+            cargo = %self.cargo_path().display(),
+            rustc = %self.rustc_path().display(),
+            rustc_wrapper = ?self.rustc_wrapper,
+            rustc_workspace_wrapper = ?self.rustc_workspace_wrapper,
+            "resolved toolchain"
+        );
+
+        Ok(self)
+    }
+
+    fn bin_path(&self, name: &str) -> PathBuf {
+        match self.cargo_home.as_ref() {
+            Some(cargo_home) => cargo_home.join("bin").join(name),
+            None => name.into(),
         }
     }
 
+    pub fn cargo_path(&self) -> PathBuf {
+        self.cargo.clone().unwrap_or_else(|| self.bin_path("cargo"))
+    }
+
+    pub fn rustc_path(&self) -> PathBuf {
+        self.rustc.clone().unwrap_or_else(|| self.bin_path("rustc"))
+    }
+
     pub fn rustc(&self) -> Command {
-        self.cargo_command("rustc")
+        let mut cmd = Command::new(self.rustc_path());
+        if self.rustc.is_none() {
+            cmd.envs(self.rustup_env());
+        }
+
+        cmd
     }
 
     pub fn cargo(&self) -> Command {
-        self.cargo_command("cargo")
+        let mut cmd = Command::new(self.cargo_path());
+        for (key, value) in self.cargo_env() {
+            cmd.env(key, value);
+        }
+
+        cmd
+    }
+
+    /// Returns a cargo command for a build (e.g. `cargo check`), which runs with the priority set
+    /// by the resource limits. The arguments from [`Toolchain::build_args`] must still be added.
+    pub fn cargo_build(&self) -> Command {
+        let mut prefix: Vec<OsString> = Vec::new();
+        if self.resources.ionice {
+            match find_program("ionice") {
+                Some(ionice) => prefix.extend([ionice.into(), "-c".into(), "3".into()]),
+                None => warn!("`ionice` not found; running builds with the default I/O priority"),
+            }
+        }
+
+        if let Some(niceness) = self.resources.nice {
+            match find_program("nice") {
+                Some(nice) => {
+                    prefix.extend([nice.into(), "-n".into(), niceness.to_string().into()])
+                }
+                None => warn!("`nice` not found; running builds with the default priority"),
+            }
+        }
+
+        let Some((program, args)) = prefix.split_first() else {
+            return self.cargo();
+        };
+
+        let mut cmd = Command::new(program);
+        cmd.args(args).arg(self.cargo_path());
+        for (key, value) in self.cargo_env() {
+            cmd.env(key, value);
+        }
+
+        cmd
+    }
+
+    /// Returns a `cargo metadata` command with the given extra options. The options are passed
+    /// here since `MetadataCommand::other_options` replaces any options set previously.
+    pub fn metadata_command(&self, mut other_options: Vec<String>) -> MetadataCommand {
+        let mut cmd = MetadataCommand::new();
+        other_options.extend(self.network_args());
+        cmd.cargo_path(self.cargo_path())
+            .other_options(other_options);
+        for (key, value) in self.cargo_env() {
+            cmd.env(key, value);
+        }
+
+        cmd
+    }
+
+    /// The environment that makes cargo use the rustc (and subcommands like `cargo-clippy`) from
+    /// the resolved toolchain, rather than whatever is on the `PATH`, along with any rustc wrappers
+    fn cargo_env(&self) -> Vec<(&'static str, OsString)> {
+        let mut vars = Vec::new();
+        if let Some(rustc) = self.rustc.as_ref() {
+            vars.push(("RUSTC", rustc.into()));
+        }
+
+        // Wrappers like `sccache` are applied to every rustc invocation cargo makes
+        if let Some(wrapper) = self.rustc_wrapper.as_ref() {
+            vars.push(("RUSTC_WRAPPER", wrapper.into()));
+        }
+
+        if let Some(wrapper) = self.rustc_workspace_wrapper.as_ref() {
+            vars.push(("RUSTC_WORKSPACE_WRAPPER", wrapper.into()));
+        }
+
+        if self.cargo.is_none() {
+            vars.extend(self.rustup_env());
+        }
+
+        if let Some(bin_dir) = self.cargo.as_ref().and_then(|cargo| cargo.parent()) {
+            let path = env::var_os("PATH").unwrap_or_default();
+            let paths = std::iter::once(bin_dir.to_path_buf()).chain(env::split_paths(&path));
+            if let Ok(path) = env::join_paths(paths) {
+                vars.push(("PATH", path));
+            }
+        }
+
+        vars
+    }
+
+    /// The environment that makes the rustup proxies use the explicitly configured toolchain, for
+    /// when its binaries couldn't be resolved
+    fn rustup_env(&self) -> Option<(&'static str, OsString)> {
+        self.toolchain
+            .as_ref()
+            .map(|toolchain| ("RUSTUP_TOOLCHAIN", toolchain.into()))
+    }
+
+    /// Returns the path to cargo's home directory, which defaults to `$HOME/.cargo`. Returns `None`
+    /// if it isn't configured and there is no home directory.
+    pub fn cargo_home(&self) -> Option<Utf8PathBuf> {
+        match self.cargo_home.clone() {
+            Some(cargo_home) => Utf8PathBuf::from_path_buf(cargo_home)
+                .inspect_err(|path| warn!(?path, "cargo home contains non-UTF-8 characters"))
+                .ok(),
+            None => home_dir().ok().map(|home| home.join(".cargo")),
+        }
+    }
+
+    /// Returns the target triple of the host, as reported by the toolchain's rustc
+    pub fn host_triple(&self) -> Result<Option<String>> {
+        let output = children::output(self.rustc().arg("-vV"), self.sysroot_timeout)?;
+        let rustc_info = String::from_utf8(output.stdout)?;
+
+        Ok(rustc_info
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .map(String::from))
+    }
+
+    /// Returns the cfgs of the host target, as reported by the toolchain's rustc
+    pub fn target_cfgs(&self) -> Result<Vec<Cfg>> {
+        let output =
+            children::output(self.rustc().arg("--print").arg("cfg"), self.sysroot_timeout)?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to query the target's cfgs: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| Ok(line.parse()?))
+            .collect()
+    }
+
+    /// Returns the path to the toolchain's sysroot
+    pub fn sysroot(&self) -> Result<Utf8PathBuf> {
+        let output = children::output(
+            self.rustc().arg("--print").arg("sysroot"),
+            self.sysroot_timeout,
+        )?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to query the sysroot: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let p: PathBuf = String::from_utf8(output.stdout)?.trim().into();
+
+        Utf8PathBuf::from_path_buf(p).map_err(|_| anyhow!("Path contains non-UTF-8 characters"))
+    }
+}
+
+/// The directory where state (and, by default, logs) are stored, relative to the home directory
+const STATE_DIR: &str = ".local/state/cargo-subspace";
+
+/// Returns the current user's home directory
+pub fn home_dir() -> Result<Utf8PathBuf> {
+    #[cfg(not(target_os = "windows"))]
+    let home = env::var("HOME")?;
+    #[cfg(target_os = "windows")]
+    let home = env::var("USERPROFILE")?;
+
+    Ok(home.into())
+}
+
+/// Reads a path from an environment variable, treating an empty value as unset
+fn env_path(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Returns the path to the program with the given name on the `PATH`, if there is one
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Returns the directory where cargo-subspace stores state that is shared between invocations
+pub fn state_dir() -> Result<Utf8PathBuf> {
+    Ok(home_dir()?.join(STATE_DIR))
+}
+
+/// Returns true if the given path is the source code of a dependency (i.e. a crate checked out by
+/// cargo from a registry or git repository), part of the source code of a toolchain's standard
+/// library, or a file generated by a build script. These paths do not belong to the workspace being
+/// edited.
+///
+/// The standard library's source code is recognized in the given sysroot, in other toolchains
+/// installed alongside it (e.g. by rustup), and in the configured `sysroot-src` directory.
+pub fn is_dependency_source(
+    path: &Utf8Path,
+    cargo_home: Option<&Utf8Path>,
+    sysroot: &Utf8Path,
+    sysroot_src: Option<&Utf8Path>,
+) -> bool {
+    let toolchains_dir = sysroot.parent();
+    // Matches `<toolchain>/lib/rustlib/src`
+    let is_toolchain_src = |dir: &Utf8Path| {
+        dir.ends_with("lib/rustlib/src")
+            && dir
+                .parent()
+                .and_then(|p| p.parent())
+                .and_then(|p| p.parent())
+                .is_some_and(|toolchain| {
+                    toolchain == sysroot || toolchain.parent() == toolchains_dir
+                })
+    };
+
+    cargo_home.is_some_and(|cargo_home| {
+        path.starts_with(cargo_home.join("registry")) || path.starts_with(cargo_home.join("git"))
+    }) || sysroot_src.is_some_and(|src| path.starts_with(src))
+        || path
+            .ancestors()
+            .any(|dir| is_toolchain_src(dir) || is_out_dir(dir))
+}
+
+/// Returns true if the given directory is a build script's `OUT_DIR`, i.e.
+/// `<target>/<profile>/build/<pkg>-<hash>/out`. Cargo's profile directories also contain a
+/// `.fingerprint` directory, which tells them apart from other directories with the same shape.
+fn is_out_dir(dir: &Utf8Path) -> bool {
+    let profile_dir = dir
+        .parent()
+        .and_then(|pkg_dir| pkg_dir.parent())
+        .filter(|build_dir| build_dir.file_name() == Some("build"))
+        .and_then(|build_dir| build_dir.parent());
+
+    dir.file_name() == Some("out")
+        && profile_dir.is_some_and(|profile_dir| profile_dir.join(".fingerprint").is_dir())
+}
+
+/// Patterns in cargo's output that indicate it needed the network, but offline mode is enabled
+const OFFLINE_ERROR_PATTERNS: &[&str] = &[
+    "--offline was specified",
+    "you're using offline mode",
+    "--frozen was passed",
+];
+
+/// Patterns in cargo's output that indicate it failed to access the network
+const NETWORK_ERROR_PATTERNS: &[&str] = &[
+    "failed to download",
+    "download of",
+    "failed to fetch",
+    "failed to update",
+    "Could not resolve host",
+    "Could not connect",
+    "spurious network error",
+    "network failure",
+    "Timeout was reached",
+];
+
+/// Returns true if the given stderr of a cargo command indicates that it failed because it could
+/// not access the network
+pub fn is_network_error(stderr: &str) -> bool {
+    OFFLINE_ERROR_PATTERNS
+        .iter()
+        .chain(NETWORK_ERROR_PATTERNS)
+        .any(|pattern| stderr.contains(pattern))
+}
+
+/// Builds the error for a cargo command that failed with the given stderr. Failures to access the
+/// network are called out explicitly, since they're otherwise easy to mistake for problems with the
+/// workspace.
+pub fn cargo_error(command: &str, stderr: &str) -> anyhow::Error {
+    let stderr = stderr.trim();
+    if OFFLINE_ERROR_PATTERNS
+        .iter()
+        .any(|pattern| stderr.contains(pattern))
+    {
+        anyhow!(
+            "`cargo {command}` needs to access the network, but offline mode is enabled. Make \
+             sure every dependency is downloaded, or disable offline mode:\n{stderr}"
+        )
+    } else if is_network_error(stderr) {
+        anyhow!(
+            "`cargo {command}` failed to access the network. If you don't have network access, \
+             enable offline mode with `--offline` or `offline = true` in \
+             `cargo-subspace.toml`:\n{stderr}"
+        )
+    } else {
+        anyhow!("`cargo {command}` exited with an error: {stderr}")
     }
 }
 
@@ -65,6 +479,140 @@ where
     Ok(())
 }
 
+/// The minimum time between two progress messages for the same step
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Reports the progress of a step with a known number of units (e.g. `Building proc macros and
+/// build scripts 12/47`). Messages are rate limited, so that large workspaces don't flood the
+/// editor with them.
+pub struct Progress {
+    step: &'static str,
+    done: usize,
+    total: usize,
+    last_reported: Option<Instant>,
+    reported_done: usize,
+}
+
+impl Progress {
+    /// Starts the given step, and reports that none of its units are done yet
+    pub fn start(step: &'static str, total: usize) -> Result<Self> {
+        let mut progress = Self {
+            step,
+            done: 0,
+            total,
+            last_reported: None,
+            reported_done: 0,
+        };
+        progress.report(None)?;
+
+        Ok(progress)
+    }
+
+    /// Marks the unit with the given name as done. Progress is reported unless it was reported
+    /// very recently, but the last unit is always reported.
+    pub fn advance(&mut self, unit: &str) -> Result<()> {
+        self.done += 1;
+        debug!(step = self.step, unit, done = self.done, total = self.total);
+
+        let is_due = self
+            .last_reported
+            .is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL);
+        if is_due || self.done >= self.total {
+            self.report(Some(unit))?;
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the step, reporting its final progress unless it was already reported. Some units
+    /// may not have been done, e.g. if the step failed or timed out.
+    pub fn finish(mut self) -> Result<()> {
+        if self.reported_done == self.done {
+            return Ok(());
+        }
+
+        self.report(None)
+    }
+
+    fn report(&mut self, unit: Option<&str>) -> Result<()> {
+        self.last_reported = Some(Instant::now());
+        self.reported_done = self.done;
+        let message = format!("{} {}/{}", self.step, self.done, self.total);
+
+        match unit {
+            Some(unit) => log_progress(format!("{message} ({unit})")),
+            None => log_progress(message),
+        }
+    }
+}
+
+/// Maps paths that start with one prefix to paths that start with another.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PathMapping {
+    pub from: Utf8PathBuf,
+    pub to: Utf8PathBuf,
+}
+
+impl PathMapping {
+    /// Returns mappings from the canonical form of each symlink among the given path's ancestors
+    /// back to the symlink, so that paths reported by cargo (which are canonical) can be mapped back
+    /// to the form of the given path. Deeper symlinks come first, since their canonical forms may
+    /// be nested inside of those of shallower ones.
+    pub fn symlinks(path: &Utf8Path) -> Vec<Self> {
+        path.ancestors()
+            .filter(|dir| dir.is_symlink())
+            .filter_map(|dir| {
+                let from = dir.canonicalize_utf8().ok()?;
+
+                Some(Self {
+                    from,
+                    to: dir.to_path_buf(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn map(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        path.strip_prefix(&self.from)
+            .ok()
+            .map(|rest| self.to.join(rest))
+    }
+}
+
+/// Returns the given path with the first matching mapping applied to it
+pub fn remap(path: &Utf8Path, mappings: &[PathMapping]) -> Utf8PathBuf {
+    mappings
+        .iter()
+        .find_map(|mapping| mapping.map(path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Applies the given path mappings to every string in the given JSON value that is a path with a
+/// mapped prefix
+pub fn remap_json(value: &mut serde_json::Value, mappings: &[PathMapping]) {
+    match value {
+        serde_json::Value::String(s) => {
+            if let Some(mapped) = mappings
+                .iter()
+                .find_map(|mapping| mapping.map(Utf8Path::new(s)))
+            {
+                *s = mapped.into_string();
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                remap_json(value, mappings);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values_mut() {
+                remap_json(value, mappings);
+            }
+        }
+        _ => (),
+    }
+}
+
 /// A wrapper around [`Path`] that can only store a file.
 #[derive(PartialEq, Clone, Copy, Debug)]
 #[repr(transparent)]
@@ -74,6 +622,11 @@ impl FilePath<'_> {
     pub fn parent(&self) -> Option<FilePath<'_>> {
         self.0.parent().map(FilePath)
     }
+
+    /// Returns the canonical form of this path, with all symlinks resolved
+    pub fn canonicalize(&self) -> Result<FilePathBuf> {
+        self.0.canonicalize_utf8()?.try_into()
+    }
 }
 
 impl From<FilePath<'_>> for PathBuf {
@@ -111,6 +664,13 @@ impl FilePathBuf {
     pub fn as_file_path(&self) -> FilePath<'_> {
         FilePath(self.0.as_path())
     }
+
+    /// Returns this path with the first matching mapping applied to it. The mapped path refers to
+    /// the same file, but possibly from a different point of view (e.g. through a symlink), so it
+    /// isn't required to exist.
+    pub fn remap(&self, mappings: &[PathMapping]) -> FilePathBuf {
+        FilePathBuf(remap(&self.0, mappings))
+    }
 }
 
 impl Display for FilePathBuf {
@@ -0,0 +1,125 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde_json::{Value, json};
+
+/// Copies the fixture with the given name into a fresh directory, so that discovering it doesn't
+/// write anything into the source tree
+fn fixture(name: &str) -> PathBuf {
+    fn copy(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path();
+            if path.is_dir() {
+                copy(&path, &to.join(entry.file_name()));
+            } else {
+                fs::copy(&path, to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let destination = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&destination);
+    copy(&source, &destination);
+
+    destination
+}
+
+/// Runs `discover` in the given fixture with the given argument (e.g. `{"buildfile": ...}`) and
+/// returns the project it emitted. The fixture is used as the home directory, so that the workspaces
+/// discovered by one test can't be found by another.
+fn discover(workspace: &Path, argument: Value) -> Value {
+    let home = PathBuf::from(env::var_os("HOME").unwrap());
+    let cargo_home = env::var_os("CARGO_HOME").map_or_else(|| home.join(".cargo"), PathBuf::from);
+    let rustup_home =
+        env::var_os("RUSTUP_HOME").map_or_else(|| home.join(".rustup"), PathBuf::from);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-subspace"))
+        .env("HOME", workspace.join("home"))
+        .env("CARGO_HOME", cargo_home)
+        .env("RUSTUP_HOME", rustup_home)
+        .arg("--offline")
+        .arg("--target-dir")
+        .arg(workspace.join("target"))
+        .arg("--log-location")
+        .arg(workspace.join("logs"))
+        .arg("discover")
+        .arg(argument.to_string())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .find(|message| message["kind"] != "progress")
+        .map(|message| {
+            assert_eq!(message["kind"], "finished", "discovery failed: {message}");
+            message["project"].clone()
+        })
+        .unwrap_or_else(|| panic!("discover did not emit a project: {stdout}"))
+}
+
+/// Returns the crate with the given name in the given project
+fn find_crate<'a>(project: &'a Value, name: &str) -> &'a Value {
+    project["crates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|krate| krate["display_name"] == name)
+        .unwrap_or_else(|| panic!("`{name}` is not loaded"))
+}
+
+/// Asserts that the crate with the given name in the given project is a proc macro that was built
+fn assert_proc_macro_built(project: &Value, name: &str) {
+    let krate = find_crate(project, name);
+    assert_eq!(krate["is_proc_macro"], true);
+
+    let dylib = krate["proc_macro_dylib_path"]
+        .as_str()
+        .unwrap_or_else(|| panic!("`{name}` was not built"));
+    assert!(Path::new(dylib).is_file(), "`{dylib}` does not exist");
+}
+
+#[test]
+fn proc_macro_dependencies_have_dylibs() {
+    let workspace = fixture("proc-macro-dependency");
+    let project = discover(
+        &workspace,
+        json!({ "buildfile": workspace.join("app/Cargo.toml") }),
+    );
+
+    assert_proc_macro_built(&project, "macros");
+    // Only used by the tests of `app`
+    assert_proc_macro_built(&project, "test_macros");
+}
+
+#[test]
+fn build_script_output_uses_workspace_project() {
+    let workspace = fixture("build-script-output");
+    let project = discover(
+        &workspace,
+        json!({ "buildfile": workspace.join("app/Cargo.toml") }),
+    );
+
+    let out_dir = find_crate(&project, "app")["env"]["OUT_DIR"]
+        .as_str()
+        .expect("the build script was run");
+    let generated = Path::new(out_dir).join("gen.rs");
+    assert!(
+        generated.is_file(),
+        "`{}` does not exist",
+        generated.display()
+    );
+
+    // The generated file isn't inside any package of the virtual workspace
+    let project = discover(&workspace, json!({ "path": generated }));
+    find_crate(&project, "app");
+}
@@ -0,0 +1,5 @@
+macros::answer!();
+
+fn main() {
+    println!("{}", answer());
+}
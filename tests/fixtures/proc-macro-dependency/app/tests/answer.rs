@@ -0,0 +1,2 @@
+#[test_macros::test]
+fn answer() {}